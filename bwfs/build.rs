@@ -0,0 +1,16 @@
+//! Records the git revision for `bwfs_bench` reports.
+
+use std::process::Command;
+
+fn main() {
+    let rev = Command::new("git")
+        .args(["describe", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BWFS_GIT_REVISION={}", rev);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+}
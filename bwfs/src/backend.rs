@@ -0,0 +1,212 @@
+//! Storage behind ImageFS: where block images and the manifest live.
+//!
+//! ImageFS addresses every object by a path under its backing root, as
+//! if the root were a directory: `<root>/block_7.png`,
+//! `<root>/manifest.json`. A [`BlockBackend`] maps those paths to actual
+//! storage and only ever sees encoded bytes; PNG coding stays in ImageFS.
+//!
+//! The backing argument picks the backend by scheme:
+//!   - `dir:PATH`, or a plain path: a local directory, read-write
+//!   - `tar:PATH`: an uncompressed tar archive holding a dumped backing
+//!     directory, read-only, for distributing a tree as one file
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+/// Size of a tar header and of the records file data is padded to.
+const TAR_RECORD: u64 = 512;
+
+/// Storage for the objects of one backing root.
+pub trait BlockBackend: Send {
+    /// Contents of the object at `path`.
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Create or overwrite the object at `path`.
+    fn save(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+
+    /// Remove the object at `path`.
+    fn delete(&self, path: &Path) -> io::Result<()>;
+
+    /// Atomically move the object at `from` to `to`, replacing `to`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Whether an object exists at `path`.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Objects directly under the root, with their sizes.
+    fn list(&self) -> io::Result<Vec<(PathBuf, u64)>>;
+
+    /// Whether `save`, `delete` and `rename` always fail with EROFS.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Open the backend named by a backing argument; returns its root. A
+/// missing directory is created if `create`.
+pub fn open(spec: &str, create: bool) -> io::Result<(PathBuf, Box<dyn BlockBackend>)> {
+    if let Some(path) = spec.strip_prefix("tar:") {
+        let root = PathBuf::from(path);
+        let tar = TarBackend::open(&root)?;
+        return Ok((root, Box::new(tar)));
+    }
+    let root = PathBuf::from(spec.strip_prefix("dir:").unwrap_or(spec));
+    if create {
+        std::fs::create_dir_all(&root)?;
+    }
+    Ok((root.clone(), Box::new(DirBackend { root })))
+}
+
+/// Objects are files under a local directory.
+pub struct DirBackend {
+    root: PathBuf,
+}
+
+impl BlockBackend for DirBackend {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn save(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        if let Some(p) = path.parent() {
+            std::fs::create_dir_all(p)?;
+        }
+        std::fs::write(path, bytes)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if let Some(p) = to.parent() {
+            std::fs::create_dir_all(p)?;
+        }
+        std::fs::rename(from, to)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self) -> io::Result<Vec<(PathBuf, u64)>> {
+        let mut out = Vec::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                out.push((entry.path(), meta.len()));
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Objects are the regular files of a tar archive, indexed once at open.
+pub struct TarBackend {
+    root: PathBuf,
+    file: File,
+    /// Data offset and size of each file, by path under the root.
+    entries: HashMap<PathBuf, (u64, u64)>,
+}
+
+impl TarBackend {
+    /// Index the ustar (or GNU/pax, minus their extensions) archive at
+    /// `root`. Member names are taken relative to the archive, so
+    /// `block_7.png` and `./block_7.png` both become `<root>/block_7.png`.
+    fn open(root: &Path) -> io::Result<Self> {
+        let file = File::open(root)?;
+        let len = file.metadata()?.len();
+        let mut entries = HashMap::new();
+        let mut header = [0u8; TAR_RECORD as usize];
+        let mut pos = 0;
+        while pos + TAR_RECORD <= len {
+            file.read_exact_at(&mut header, pos)?;
+            // The archive ends with zero records.
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            let size = tar_octal(&header[124..136])
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad tar header at {}", pos)))?;
+            let data = pos + TAR_RECORD;
+            // Regular files only; directories and pax/GNU extension
+            // records are skipped.
+            if matches!(header[156], b'0' | 0) {
+                let mut name = tar_field(&header[0..100]);
+                if &header[257..262] == b"ustar" {
+                    let prefix = tar_field(&header[345..500]);
+                    if !prefix.is_empty() {
+                        name = format!("{}/{}", prefix, name);
+                    }
+                }
+                let name = name.trim_start_matches("./");
+                if !name.is_empty() {
+                    entries.insert(root.join(name), (data, size));
+                }
+            }
+            pos = data + size.div_ceil(TAR_RECORD) * TAR_RECORD;
+        }
+        Ok(Self { root: root.to_path_buf(), file, entries })
+    }
+}
+
+/// NUL-terminated string field of a tar header.
+fn tar_field(field: &[u8]) -> String {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..len]).into_owned()
+}
+
+/// Octal number field of a tar header, padded with spaces or NULs.
+fn tar_octal(field: &[u8]) -> Option<u64> {
+    let s = tar_field(field);
+    let s = s.trim_matches(' ');
+    if s.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(s, 8).ok()
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::from_raw_os_error(libc::EROFS)
+}
+
+impl BlockBackend for TarBackend {
+    fn load(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let &(offset, size) = self.entries.get(path).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut buf = vec![0u8; size as usize];
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    fn save(&self, _path: &Path, _bytes: &[u8]) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn delete(&self, _path: &Path) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(read_only_error())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    fn list(&self) -> io::Result<Vec<(PathBuf, u64)>> {
+        Ok(self
+            .entries
+            .iter()
+            .filter(|(p, _)| p.parent() == Some(self.root.as_path()))
+            .map(|(p, &(_, size))| (p.clone(), size))
+            .collect())
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
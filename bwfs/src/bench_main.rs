@@ -0,0 +1,291 @@
+//! bwfs-bench: a fixed micro-benchmark suite for a mounted filesystem.
+//!
+//! Every test runs through the ordinary file APIs in a scratch directory
+//! under the mount point, so the numbers include the FUSE round trips and
+//! the block encoding behind them:
+//!   - seq-write / seq-read: one large file, 1 MiB per call
+//!   - rand-write / rand-read: 4 KiB at random aligned offsets of that file
+//!   - create-delete: empty files created and unlinked one after another
+//!   - readdir: listing a directory of `--files` entries
+//!   - untar: nested directories of small files with their mtime set, as
+//!     tar does when extracting
+//!
+//! Each test reports throughput and per-call latency percentiles. The
+//! report also carries the git revision the tool was built from and the
+//! options the filesystem is mounted with, so runs can be compared over
+//! time. A test that fails (e.g. an operation the filesystem does not
+//! implement) is reported with its error and the suite carries on.
+//!
+//! Usage:
+//!     bwfs_bench /mnt/bwfs [--size-mb 64] [--files 500] [--ops 1000] [--json]
+
+use clap::Parser;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Size of one sequential IO call.
+const SEQ_CHUNK: usize = 1 << 20;
+/// Size of one random IO call.
+const RAND_CHUNK: usize = 4096;
+/// Payload of each file in the untar test.
+const UNTAR_FILE_BYTES: usize = 2048;
+/// Files per directory in the untar test.
+const UNTAR_FANOUT: usize = 50;
+/// How often the readdir test lists its directory.
+const READDIR_PASSES: usize = 5;
+
+#[derive(Parser)]
+struct Cli {
+    /// Mount point of the filesystem under test.
+    mountpoint: PathBuf,
+
+    /// Size of the sequential test file, in MiB.
+    #[arg(long, default_value_t = 64)]
+    size_mb: u64,
+
+    /// Files for the create, readdir and untar tests.
+    #[arg(long, default_value_t = 500)]
+    files: usize,
+
+    /// Calls for each random IO test.
+    #[arg(long, default_value_t = 1000)]
+    ops: usize,
+
+    /// Print the report as JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Report {
+    git_revision: &'static str,
+    mountpoint: String,
+    /// Filesystem type and options from /proc/self/mounts.
+    mount_options: Option<String>,
+    results: Vec<TestResult>,
+}
+
+#[derive(Serialize)]
+struct TestResult {
+    name: &'static str,
+    ops: usize,
+    bytes: u64,
+    secs: f64,
+    p50_us: u64,
+    p99_us: u64,
+    max_us: u64,
+    error: Option<String>,
+}
+
+/// Latencies and byte count collected while a test runs.
+#[derive(Default)]
+struct Sample {
+    latencies: Vec<Duration>,
+    bytes: u64,
+}
+
+impl Sample {
+    /// Run and time one call.
+    fn time<T>(&mut self, op: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        let start = Instant::now();
+        let res = op();
+        self.latencies.push(start.elapsed());
+        res
+    }
+}
+
+/// Run one test, turning its error into part of the result.
+fn run(name: &'static str, test: impl FnOnce(&mut Sample) -> io::Result<()>) -> TestResult {
+    let mut sample = Sample::default();
+    let start = Instant::now();
+    let error = test(&mut sample).err().map(|e| e.to_string());
+    let secs = start.elapsed().as_secs_f64();
+
+    let mut lat = sample.latencies;
+    lat.sort();
+    let pct = |p: usize| lat.get((lat.len() * p / 100).min(lat.len().saturating_sub(1)));
+    let us = |d: Option<&Duration>| d.map_or(0, |d| d.as_micros() as u64);
+    TestResult {
+        name,
+        ops: lat.len(),
+        bytes: sample.bytes,
+        secs,
+        p50_us: us(pct(50)),
+        p99_us: us(pct(99)),
+        max_us: us(lat.last()),
+        error,
+    }
+}
+
+/// Deterministic xorshift, so every run touches the same offsets.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+fn seq_write(s: &mut Sample, path: &Path, size: u64) -> io::Result<()> {
+    let buf: Vec<u8> = (0..SEQ_CHUNK).map(|i| (i % 251) as u8).collect();
+    let mut f = File::create(path)?;
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(SEQ_CHUNK as u64) as usize;
+        s.time(|| f.write_all(&buf[..n]))?;
+        s.bytes += n as u64;
+        left -= n as u64;
+    }
+    s.time(|| f.sync_all())
+}
+
+fn seq_read(s: &mut Sample, path: &Path) -> io::Result<()> {
+    let mut buf = vec![0u8; SEQ_CHUNK];
+    let mut f = File::open(path)?;
+    loop {
+        let n = s.time(|| f.read(&mut buf))?;
+        if n == 0 {
+            return Ok(());
+        }
+        s.bytes += n as u64;
+    }
+}
+
+fn rand_io(s: &mut Sample, path: &Path, size: u64, ops: usize, write: bool) -> io::Result<()> {
+    let f = OpenOptions::new().read(true).write(write).open(path)?;
+    let slots = (size / RAND_CHUNK as u64).max(1);
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    let mut buf = vec![0x5au8; RAND_CHUNK];
+    for _ in 0..ops {
+        let off = rng.next() % slots * RAND_CHUNK as u64;
+        if write {
+            s.time(|| f.write_all_at(&buf, off))?;
+        } else {
+            s.time(|| f.read_exact_at(&mut buf, off))?;
+        }
+        s.bytes += RAND_CHUNK as u64;
+    }
+    Ok(())
+}
+
+fn create_delete(s: &mut Sample, dir: &Path, files: usize) -> io::Result<()> {
+    fs::create_dir(dir)?;
+    for i in 0..files {
+        let path = dir.join(format!("f{}", i));
+        s.time(|| File::create(&path))?;
+        s.time(|| fs::remove_file(&path))?;
+    }
+    Ok(())
+}
+
+fn readdir(s: &mut Sample, dir: &Path, files: usize) -> io::Result<()> {
+    fs::create_dir(dir)?;
+    for i in 0..files {
+        File::create(dir.join(format!("entry_{:06}", i)))?;
+    }
+    for _ in 0..READDIR_PASSES {
+        let listed = s.time(|| fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>())?;
+        if listed.len() != files {
+            return Err(io::Error::other(format!("listed {} of {} entries", listed.len(), files)));
+        }
+    }
+    Ok(())
+}
+
+fn untar(s: &mut Sample, dir: &Path, files: usize) -> io::Result<()> {
+    let payload = vec![0xa5u8; UNTAR_FILE_BYTES];
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    for i in 0..files {
+        let sub = dir.join(format!("pkg{}", i / UNTAR_FANOUT)).join(format!("src{}", i % 5));
+        let path = sub.join(format!("file{}.c", i));
+        s.time(|| {
+            fs::create_dir_all(&sub)?;
+            let mut f = File::create(&path)?;
+            f.write_all(&payload)?;
+            f.set_modified(mtime)
+        })?;
+        s.bytes += UNTAR_FILE_BYTES as u64;
+    }
+    Ok(())
+}
+
+/// Type and options of the filesystem mounted at `mountpoint`.
+fn mount_options(mountpoint: &Path) -> Option<String> {
+    let target = fs::canonicalize(mountpoint).ok()?;
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    mounts.lines().rev().find_map(|line| {
+        let f: Vec<&str> = line.split_whitespace().collect();
+        (f.len() >= 4 && Path::new(f[1]) == target).then(|| format!("{} {}", f[2], f[3]))
+    })
+}
+
+fn print_table(report: &Report) {
+    println!("bwfs_bench {} on {}", report.git_revision, report.mountpoint);
+    println!("mount: {}", report.mount_options.as_deref().unwrap_or("<unknown>"));
+    println!();
+    println!(
+        "{:<14} {:>8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "test", "ops", "MiB/s", "ops/s", "p50 us", "p99 us", "max us"
+    );
+    for r in &report.results {
+        if let Some(e) = &r.error {
+            println!("{:<14} failed after {} ops: {}", r.name, r.ops, e);
+            continue;
+        }
+        let secs = r.secs.max(f64::EPSILON);
+        println!(
+            "{:<14} {:>8} {:>10.1} {:>10.0} {:>10} {:>10} {:>10}",
+            r.name,
+            r.ops,
+            r.bytes as f64 / (1 << 20) as f64 / secs,
+            r.ops as f64 / secs,
+            r.p50_us,
+            r.p99_us,
+            r.max_us
+        );
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let scratch = cli.mountpoint.join(format!("bwfs_bench.{}", std::process::id()));
+    if let Err(e) = fs::create_dir(&scratch) {
+        eprintln!("bwfs_bench: cannot create {}: {}", scratch.display(), e);
+        std::process::exit(1);
+    }
+
+    let size = cli.size_mb << 20;
+    let seq = scratch.join("seq");
+    let results = vec![
+        run("seq-write", |s| seq_write(s, &seq, size)),
+        run("seq-read", |s| seq_read(s, &seq)),
+        run("rand-write", |s| rand_io(s, &seq, size, cli.ops, true)),
+        run("rand-read", |s| rand_io(s, &seq, size, cli.ops, false)),
+        run("create-delete", |s| create_delete(s, &scratch.join("storm"), cli.files)),
+        run("readdir", |s| readdir(s, &scratch.join("list"), cli.files)),
+        run("untar", |s| untar(s, &scratch.join("untar"), cli.files)),
+    ];
+
+    if let Err(e) = fs::remove_dir_all(&scratch) {
+        eprintln!("bwfs_bench: cannot remove {}: {}", scratch.display(), e);
+    }
+
+    let report = Report {
+        git_revision: env!("BWFS_GIT_REVISION"),
+        mountpoint: cli.mountpoint.display().to_string(),
+        mount_options: mount_options(&cli.mountpoint),
+        results,
+    };
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print_table(&report);
+    }
+}
@@ -1,27 +1,135 @@
 use fuser::{
     Filesystem, Request,
     ReplyAttr, ReplyCreate, ReplyOpen, ReplyData, ReplyWrite, ReplyEmpty, ReplyEntry,
-    ReplyStatfs, ReplyLseek, FileAttr, FileType, MountOption,
+    ReplyStatfs, ReplyLseek, ReplyDirectory, ReplyXattr, ReplyIoctl, ReplyPoll, FileAttr, FileType, MountOption, Notifier,
+    PollHandle, SessionUnmounter,
 };
-use libc::{ENOENT, EEXIST, EINVAL};
+use libc::{c_int, ENOENT, EEXIST, EINVAL, EDQUOT, EISDIR, ENOTDIR, EPERM, EACCES, ENAMETOOLONG, R_OK, W_OK, X_OK};
 use std::{
-    collections::HashMap,
-    env,
+    collections::{hash_map::{DefaultHasher, Entry}, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
     ffi::OsStr,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
-    time::{Duration, SystemTime},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use clap::{Parser, ValueEnum};
 use image::{GrayImage, ImageBuffer, Luma};
 use std::io;
+use unicode_normalization::UnicodeNormalization;
+
+mod backend;
+mod manifest;
+mod retry;
+// Listing and checking records is left to bwfs_mounts.
+#[allow(dead_code)]
+mod runtime;
+mod signals;
+mod throttle;
+mod trace;
+
+use backend::BlockBackend;
+use manifest::{Manifest, ManifestNode};
+use retry::RetryPolicy;
+use runtime::MountRecord;
+use signals::Signal;
+use throttle::{Throttle, ThrottleLimits};
+use trace::{TraceRecord, Tracer};
 
 const BLOCK_W: usize = 1000;
 const BLOCK_H: usize = 1000;
 const BLOCK_BYTES: usize = BLOCK_W * BLOCK_H;
 const TTL: Duration = Duration::from_secs(1);
 
+/// Longest full path ImageFS will store, matching the kernel's PATH_MAX.
+///
+/// Every node is keyed by its full path, so without a cap a runaway
+/// `mkdir` loop makes each lookup slower and slower.
+const PATH_MAX: usize = 4096;
+/// Deepest nesting ImageFS will store, in path components.
+const MAX_PATH_DEPTH: usize = 256;
+/// Longest name of a single entry, in bytes; reported by statfs.
+const NAME_MAX: usize = 255;
+
+/// How long a block may stay dirty before the background flusher encodes
+/// it. close() does not write data back, so this bounds how stale the
+/// backing directory gets between fsyncs.
+const TAIL_FLUSH_DELAY: Duration = Duration::from_secs(5);
+
+/// Hidden directory that receives unlinked files in trash mode.
+const TRASH_DIR: &str = "/.bwfs_trash";
+/// How often the background reaper looks for expired trash entries.
+const TRASH_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Setting this extended attribute on `TRASH_DIR`, to any value, frees
+/// every trashed file at once:
+/// `setfattr -n user.bwfs.empty_trash -v 1 /mnt/.bwfs_trash`.
+const EMPTY_TRASH_XATTR: &str = "user.bwfs.empty_trash";
+
+/// Directory of the backing directory that `--gc` moves orphaned block
+/// images into.
+const LOST_FOUND_DIR: &str = "lost+found";
+
+/// Bounds for the st_blksize ImageFS advertises.
+///
+/// Tools size their buffers from st_blksize: a whole block would have
+/// them allocate a megabyte per stat, tiny values make them do many small
+/// IOs. The upper bound matches the kernel's default FUSE write size, so
+/// a write of st_blksize bytes arrives as a single request.
+const MIN_IO_SIZE: u32 = 4096;
+const MAX_IO_SIZE: u32 = 128 * 1024;
+
+/// Default for `--max-file-size`: 1 TiB.
+const DEFAULT_MAX_FILE_SIZE: u64 = 1 << 40;
+/// Default for `--max-dirty`: 256 MiB of dirty blocks across all files.
+const DEFAULT_MAX_DIRTY: u64 = 256 << 20;
+
+/// Exit statuses, following the convention of the mkfs.bwfs tools.
+const EXIT_FAILURE: i32 = 1;
+const EXIT_CONFIG: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_MOUNTED: i32 = 6;
+
+/// Block indices are `usize`, so on 32-bit targets they, not the byte
+/// offsets, are what limits a file. Sizes stay `u64` everywhere else.
+const MAX_INDEXED_FILE_SIZE: u64 = (usize::MAX as u64).saturating_mul(BLOCK_BYTES as u64);
+
+/// Read-only extended attributes computed from a node's state.
+///
+/// - `user.bwfs.blocks`: backing block image paths, one per line, so
+///   backup scripts can copy only the PNGs behind a file.
+/// - `user.bwfs.dirty`: number of blocks not yet written back.
+/// - `user.bwfs.write_amp`: bytes written, bytes of PNG encoded and
+///   blocks re-encoded for the file, and their ratio.
+/// - `user.bwfs.stats`: read and write counters since the mount, with
+///   `--io-stats`.
+const VIRTUAL_XATTRS: &[&str] = &["user.bwfs.blocks", "user.bwfs.dirty", "user.bwfs.write_amp", "user.bwfs.stats"];
+
+/// Files listed in the write amplification summary at unmount.
+const WRITE_AMP_REPORTED_FILES: usize = 5;
+
+/// Files listed in the most-read summary at unmount, with `--io-stats`.
+const IO_STATS_REPORTED_FILES: usize = 10;
+
+/// Offset and length alignment required of O_DIRECT reads and writes.
+///
+/// Like a local filesystem on a 512-byte-sector disk, unaligned direct IO
+/// fails with EINVAL instead of silently going through a bounce buffer.
+const DIRECT_IO_ALIGN: u64 = 512;
+
+/// `FITRIM`, i.e. `_IOWR('X', 121, struct fstrim_range)`.
+const FITRIM: u32 = 0xC018_5879;
+
 type Inode = u64;
 type FH = u64;
+/// Requesting (uid, gid), as carried by the FUSE request.
+type Caller = (u32, u32);
+
+/// One entry of a directory listing.
+struct DirEntry {
+    ino: Inode,
+    kind: FileType,
+    name: String,
+}
 
 #[derive(Clone, Debug)]
 struct FileNode {
@@ -29,13 +137,37 @@ struct FileNode {
     name: String,
     is_dir: bool,
     size: u64,
-    blocks: Vec<PathBuf>,
+    /// Block images by index. Indexes without an entry are holes: they
+    /// read as zeros and cost neither memory nor a block id.
+    blocks: BTreeMap<usize, PathBuf>,
     dirty: HashMap<usize, Vec<u8>>,
+    /// When each dirty block was first modified since its last save.
+    dirty_at: HashMap<usize, Instant>,
+    /// Content hash of each block as last written to its PNG.
+    saved_hashes: HashMap<usize, u64>,
+    /// Blocks the manifest lists but whose PNG is gone; reads return EIO.
+    missing: HashSet<usize>,
+    /// Number of subdirectories; a directory's link count is this plus 2.
+    subdirs: u32,
+    /// Error of the last background write-back, reported by the next
+    /// flush (close) and cleared by a successful fsync.
+    writeback_error: Option<c_int>,
+    /// Background write-backs in a row that failed transiently; each
+    /// is retried on the next flusher pass until `--io-retries` is used up.
+    flush_retries: u32,
+    /// Write amplification of this file since the mount.
+    write_amp: WriteAmp,
+    /// Reads and writes since the mount; only kept with `--io-stats`.
+    io_stats: IoStats,
     perm: u32,
     atime: SystemTime,
     mtime: SystemTime,
     ctime: SystemTime,
+    /// Birth time: set at creation and only changed by an explicit setattr.
+    crtime: SystemTime,
     mode: u32,
+    uid: u32,
+    gid: u32,
 }
 
 impl FileNode {
@@ -45,363 +177,1500 @@ impl FileNode {
             ino,
             name: name.to_string(),
             is_dir,
-            size: if is_dir { 0 } else { 0 },
-            blocks: vec![],
+            size: 0,
+            blocks: BTreeMap::new(),
             dirty: HashMap::new(),
+            dirty_at: HashMap::new(),
+            saved_hashes: HashMap::new(),
+            missing: HashSet::new(),
+            subdirs: 0,
+            writeback_error: None,
+            flush_retries: 0,
+            write_amp: WriteAmp::default(),
+            io_stats: IoStats::default(),
             perm,
             atime: now,
             mtime: now,
             ctime: now,
+            crtime: now,
             mode: 0,
+            uid: 1000,
+            gid: 1000,
         }
     }
 
-    fn attr(&self) -> FileAttr {
+    /// Attributes as reported to the kernel; `io_size` becomes st_blksize.
+    fn attr(&self, io_size: u32) -> FileAttr {
         FileAttr {
             ino: self.ino,
             size: self.size,
-            blocks: ((self.size + (BLOCK_BYTES as u64) - 1) / (BLOCK_BYTES as u64)) as u64,
+            // st_blocks counts 512-byte units; holes take no space.
+            blocks: self.blocks.len() as u64 * (BLOCK_BYTES as u64 / 512),
             atime: self.atime,
             mtime: self.mtime,
             ctime: self.ctime,
-            crtime: self.ctime,
+            crtime: self.crtime,
             kind: if self.is_dir { FileType::Directory } else { FileType::RegularFile },
             perm: (self.perm & 0o7777) as u16,
-            nlink: if self.is_dir { 2 } else { 1 },
-            uid: 1000,
-            gid: 1000,
+            nlink: if self.is_dir { 2 + self.subdirs } else { 1 },
+            uid: self.uid,
+            gid: self.gid,
             rdev: 0,
             flags: 0,
-            blksize: BLOCK_BYTES as u32,
+            blksize: io_size,
+        }
+    }
+
+    /// Block image names for the manifest, by index; holes are "".
+    fn manifest_blocks(&self) -> Vec<String> {
+        let len = self.blocks.keys().next_back().map_or(0, |&last| last + 1);
+        let mut names = vec![String::new(); len];
+        for (&idx, path) in &self.blocks {
+            names[idx] = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        }
+        names
+    }
+
+    /// Whether block `idx` holds no data: past the block list, or never
+    /// written, so neither dirty nor present in the backing directory.
+    /// Blocks whose image went missing count as data; reading them fails.
+    fn is_hole(&self, idx: usize, backend: &dyn BlockBackend) -> bool {
+        match self.blocks.get(&idx) {
+            None => true,
+            Some(path) => {
+                !self.dirty.contains_key(&idx) && !self.missing.contains(&idx) && !backend.exists(path)
+            }
+        }
+    }
+
+    /// Answer SEEK_DATA (`want_data`) or SEEK_HOLE from `offset`.
+    ///
+    /// Granularity is one block. Like local filesystems, ENXIO at or past
+    /// EOF and for SEEK_DATA in a trailing hole; SEEK_HOLE always finds
+    /// the implicit hole at EOF.
+    fn seek_data_hole(&self, offset: u64, want_data: bool, backend: &dyn BlockBackend) -> Result<u64, c_int> {
+        if offset >= self.size {
+            return Err(libc::ENXIO);
+        }
+        let last = self.size.div_ceil(BLOCK_BYTES as u64) as usize;
+        let first = (offset / BLOCK_BYTES as u64) as usize;
+        match (first..last).find(|&idx| self.is_hole(idx, backend) != want_data) {
+            Some(idx) => Ok(offset.max(idx as u64 * BLOCK_BYTES as u64)),
+            None if want_data => Err(libc::ENXIO),
+            None => Ok(self.size),
+        }
+    }
+
+    /// Value of the virtual extended attribute `name`, if it is one.
+    fn virtual_xattr(&self, name: &str) -> Option<Vec<u8>> {
+        match name {
+            "user.bwfs.blocks" => {
+                let paths: Vec<String> = self.blocks.values().map(|p| p.display().to_string()).collect();
+                Some(paths.join("\n").into_bytes())
+            }
+            "user.bwfs.dirty" => Some(self.dirty.len().to_string().into_bytes()),
+            "user.bwfs.write_amp" => {
+                let a = &self.write_amp;
+                Some(
+                    format!(
+                        "written {}\nencoded {}\nblocks {}\nratio {:.2}",
+                        a.logical_bytes, a.encoded_bytes, a.blocks_encoded, a.ratio()
+                    )
+                    .into_bytes(),
+                )
+            }
+            "user.bwfs.stats" => {
+                let s = &self.io_stats;
+                let last_uid = s.last_uid.map_or("-".to_string(), |u| u.to_string());
+                Some(
+                    format!(
+                        "reads {}\nwrites {}\nbytes_read {}\nbytes_written {}\nlast_uid {}",
+                        s.reads, s.writes, s.bytes_read, s.bytes_written, last_uid
+                    )
+                    .into_bytes(),
+                )
+            }
+            _ => None,
+        }
+    }
+
+    /// Evaluate an access(2)-style `mask` for `caller` against the mode bits.
+    ///
+    /// The owner, group or other class is picked exactly as the kernel
+    /// does. Root bypasses read/write checks, but execute on a regular file
+    /// still needs at least one execute bit.
+    fn check_access(&self, (uid, gid): Caller, mask: i32) -> Result<(), c_int> {
+        if uid == 0 {
+            if mask & X_OK != 0 && !self.is_dir && self.perm & 0o111 == 0 {
+                return Err(EACCES);
+            }
+            return Ok(());
+        }
+        let shift = if uid == self.uid {
+            6
+        } else if gid == self.gid {
+            3
+        } else {
+            0
+        };
+        let class = (self.perm >> shift) & 0o7;
+        let wanted = (mask & (R_OK | W_OK | X_OK)) as u32;
+        if wanted & !class != 0 {
+            return Err(EACCES);
+        }
+        Ok(())
+    }
+}
+
+/// Full path of the directory holding `full`.
+fn parent_of(full: &str) -> &str {
+    match full.rfind('/') {
+        Some(0) | None => "/",
+        Some(i) => &full[..i],
+    }
+}
+
+/// Numeric id of a `block_<id>.png` file name.
+fn block_id(name: &str) -> Option<u64> {
+    name.strip_prefix("block_")?.strip_suffix(".png")?.parse().ok()
+}
+
+/// Hash used to detect blocks that are unchanged since their last save.
+fn block_hash(buf: &[u8]) -> u64 {
+    let mut h = DefaultHasher::new();
+    buf.hash(&mut h);
+    h.finish()
+}
+
+/// ENAMETOOLONG if `full` exceeds `PATH_MAX` bytes or `MAX_PATH_DEPTH` components.
+fn check_path_limits(full: &str) -> Result<(), c_int> {
+    let depth = full.split('/').filter(|c| !c.is_empty()).count();
+    if full.len() > PATH_MAX || depth > MAX_PATH_DEPTH {
+        return Err(ENAMETOOLONG);
+    }
+    Ok(())
+}
+
+/// Check that `mountpoint` can be mounted on without surprises: nothing
+/// may be mounted there already, and it must be empty unless
+/// `allow_nonempty`, since the mount would hide its contents.
+///
+/// Errors carry the exit status to report them with.
+fn validate_mountpoint(mountpoint: &str, allow_nonempty: bool) -> Result<(), (i32, String)> {
+    let io_err = |path: &Path, e: io::Error| {
+        let code = if e.kind() == io::ErrorKind::NotFound { EXIT_NOT_FOUND } else { EXIT_FAILURE };
+        (code, format!("{}: {}", path.display(), e))
+    };
+    let path = std::path::absolute(mountpoint).map_err(|e| io_err(Path::new(mountpoint), e))?;
+    // A dead FUSE mount cannot be resolved, but it is still in mountinfo.
+    let path = path.canonicalize().unwrap_or(path);
+    if let Some((fstype, source)) = existing_mount(&path) {
+        return Err((
+            EXIT_MOUNTED,
+            format!("{} is already a mount point ({} from {}); unmount it first", path.display(), fstype, source),
+        ));
+    }
+    let mut entries = std::fs::read_dir(&path).map_err(|e| io_err(&path, e))?;
+    if entries.next().is_some() {
+        if !allow_nonempty {
+            return Err((
+                EXIT_CONFIG,
+                format!(
+                    "{} is not empty and mounting would hide its contents; pass --allow-nonempty to mount anyway",
+                    path.display()
+                ),
+            ));
+        }
+        eprintln!("imgfs: {} is not empty; its contents are hidden while mounted", path.display());
+    }
+    Ok(())
+}
+
+/// Filesystem type and source of the mount at exactly `path`, from
+/// /proc/self/mountinfo.
+fn existing_mount(path: &Path) -> Option<(String, String)> {
+    let info = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    info.lines().rev().find_map(|line| {
+        // Optional fields come before the " - " separator, so the mount
+        // point is counted from the front and the type from the back.
+        let (fields, tail) = line.split_once(" - ")?;
+        if Path::new(&unescape_mountinfo(fields.split(' ').nth(4)?)) != path {
+            return None;
+        }
+        let mut tail = tail.split(' ');
+        Some((tail.next()?.to_string(), unescape_mountinfo(tail.next()?)))
+    })
+}
+
+/// Undo the octal escapes mountinfo uses for spaces, tabs, newlines and
+/// backslashes in paths.
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'\\')
+            .then(|| bytes.get(i + 1..i + 4))
+            .flatten()
+            .and_then(|d| u8::from_str_radix(std::str::from_utf8(d).ok()?, 8).ok());
+        match escaped {
+            Some(b) => {
+                out.push(b);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Whether something is still mounted on `path`: it then lives on a
+/// different device than its parent directory.
+fn still_mounted(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let parent = path.parent().unwrap_or(Path::new("/"));
+    match (std::fs::metadata(path), std::fs::metadata(parent)) {
+        (Ok(m), Ok(p)) => m.dev() != p.dev(),
+        _ => false,
+    }
+}
+
+/// Answer a getxattr/listxattr request for `data`.
+///
+/// A `size` of 0 asks for the length only; a buffer that is too small
+/// gets ERANGE.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// FOPEN_* reply flags for a file opened with `flags`.
+///
+/// O_DIRECT handles get FOPEN_DIRECT_IO, so the kernel page cache is
+/// skipped and every read and write reaches ImageFS.
+fn open_reply_flags(flags: i32) -> u32 {
+    if flags & libc::O_DIRECT != 0 {
+        fuser::consts::FOPEN_DIRECT_IO
+    } else {
+        0
+    }
+}
+
+/// Whether a direct IO at `offset` of `len` bytes is suitably aligned.
+fn direct_aligned(offset: i64, len: u64) -> bool {
+    (offset as u64).is_multiple_of(DIRECT_IO_ALIGN) && len.is_multiple_of(DIRECT_IO_ALIGN)
+}
+
+/// Access mask needed to open a file with the given open(2) flags.
+fn open_mask(flags: i32) -> i32 {
+    match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => W_OK,
+        libc::O_RDWR => R_OK | W_OK,
+        _ => R_OK,
+    }
+}
+
+/// Permission bits of a node created with `mode` under `umask`: the
+/// file type bits go, as do the bits the umask clears.
+fn create_perm(mode: u32, umask: u32) -> u32 {
+    mode & !umask & 0o7777
+}
+
+/// Mount-time behaviour switches, taken from the command line.
+#[derive(Clone, Debug)]
+struct MountOptions {
+    /// Move unlinked files into `TRASH_DIR` instead of freeing them.
+    trash: bool,
+    /// How long a trashed file is kept before its blocks are freed.
+    trash_retention: Duration,
+    /// Block limits per uid, from `--quota UID=BLOCKS`.
+    quota_limits: HashMap<u32, u64>,
+    /// Read, write and IOPS caps for the whole mount.
+    throttle: ThrottleLimits,
+    /// Retry policy for block loads and saves.
+    retry: RetryPolicy,
+    /// Match names case-insensitively, preserving the case they were
+    /// created with.
+    casefold: bool,
+    /// Unicode form incoming names are normalized to, from `--normalize`.
+    normalize: Option<NameForm>,
+    /// Preferred IO size reported as st_blksize and statfs f_bsize.
+    io_size: u32,
+    /// Largest file size accepted, from `--max-file-size`.
+    max_file_size: u64,
+    /// Ordering of data writes against manifest updates, from `--data`.
+    data_mode: DataMode,
+    /// Write a file's dirty blocks back on every close, from
+    /// `--flush-on-close`.
+    flush_on_close: bool,
+    /// Make renames that replace a file durable; off with
+    /// `--no-rename-barrier`.
+    rename_barrier: bool,
+    /// Dirty blocks kept in memory across all files before writes start
+    /// writing back the oldest ones, from `--max-dirty`.
+    max_dirty_blocks: usize,
+    /// Count reads and writes per file, from `--io-stats`.
+    io_stats: bool,
+    /// Also record the uid of each file's last reader or writer, from
+    /// `--audit`.
+    audit: bool,
+}
+
+/// When dirty data is written relative to the manifest that references it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum DataMode {
+    /// Write back every dirty block before the manifest is replaced, so a
+    /// crash never leaves the manifest pointing at blocks that were never
+    /// written or sizes covering data that is not there.
+    Ordered,
+    /// Only the fsynced file is written first; other files' blocks may
+    /// reach the backing directory after the manifest that covers them.
+    Writeback,
+}
+
+/// What `--gc` does with block images no node references.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum GcMode {
+    /// Move them into `lost+found` in the backing directory.
+    Move,
+    /// Delete them.
+    Delete,
+}
+
+/// Unicode normalization forms accepted by `--normalize`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum NameForm {
+    /// Canonical composition, as typed on Linux and Windows.
+    Nfc,
+}
+
+/// How much of a node's dirty state `sync_node` writes back.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SyncMode {
+    /// Everything, as fsync requires.
+    All,
+    /// Everything except a partially filled tail block that was dirtied
+    /// less than `TAIL_FLUSH_DELAY` ago, so appends coalesce across closes.
+    DeferTail,
+    /// Only blocks `first..=last`, for write-through of O_DIRECT writes.
+    Range(usize, usize),
+}
+
+/// Block encoding counters, reported at unmount.
+#[derive(Default)]
+struct EncodeStats {
+    amp: WriteAmp,
+    /// Dirty blocks whose content matched the last saved PNG.
+    encodes_skipped: u64,
+}
+
+/// Per-file IO counters, kept in memory for `--io-stats`.
+#[derive(Clone, Copy, Debug, Default)]
+struct IoStats {
+    reads: u64,
+    writes: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+    /// Uid of the last read or write, recorded with `--audit`.
+    last_uid: Option<u32>,
+}
+
+/// Logical bytes written against what reaching the backing directory
+/// cost. A write of any size eventually re-encodes its whole block, so
+/// the ratio shows how well the workload suits the block geometry.
+#[derive(Clone, Copy, Debug, Default)]
+struct WriteAmp {
+    /// Sum of write() lengths.
+    logical_bytes: u64,
+    /// Sum of the sizes of the PNGs saved.
+    encoded_bytes: u64,
+    blocks_encoded: u64,
+}
+
+impl WriteAmp {
+    /// Account one saved block image of `bytes` bytes.
+    fn encoded(&mut self, bytes: u64) {
+        self.encoded_bytes += bytes;
+        self.blocks_encoded += 1;
+    }
+
+    /// Bytes encoded per byte written; 0 before anything was written.
+    fn ratio(&self) -> f64 {
+        match self.logical_bytes {
+            0 => 0.0,
+            n => self.encoded_bytes as f64 / n as f64,
         }
     }
 }
 
+/// Block accounting for one uid; `limit` of `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default)]
+struct Quota {
+    used_blocks: u64,
+    limit: Option<u64>,
+}
+
 struct FilesystemState {
+    /// Root the block PNGs and the manifest are addressed under.
+    backing: PathBuf,
+    /// Storage holding them, chosen by the backing argument's scheme.
+    backend: Box<dyn BlockBackend>,
     next_ino: Inode,
+    /// Id of the next block image to allocate.
+    next_block: u64,
+    /// Node for each path, keyed by `path_key` of its full path.
     path_map: HashMap<String, Inode>,
     nodes: HashMap<Inode, FileNode>,
+    /// Open file handles with the flags they were opened with.
     handles: HashMap<FH, (Inode, i32)>,
+    /// Open directory handles with the listing taken at opendir.
+    dir_handles: HashMap<FH, Vec<DirEntry>>,
+    next_fh: FH,
+    opts: MountOptions,
+    /// Files currently sitting in the trash, with the time they were unlinked.
+    trashed: HashMap<Inode, SystemTime>,
+    /// Blocks charged to each file owner.
+    quotas: HashMap<u32, Quota>,
+    encode_stats: EncodeStats,
+    /// Operations repeated after a transient backing-store error.
+    io_retries: u64,
+    /// Writes that found `max_dirty_blocks` reached.
+    dirty_limit_hits: u64,
+    /// Blocks written back early to stay under `max_dirty_blocks`.
+    forced_flushes: u64,
+    /// Images of freed blocks, deleted after the next manifest save.
+    pending_deletes: Vec<PathBuf>,
 }
 
 impl FilesystemState {
-    fn new(_backing: PathBuf) -> Self {
+    /// Start from the manifest in `backing`, or an empty tree without one.
+    fn new(backing: PathBuf, backend: Box<dyn BlockBackend>, opts: MountOptions) -> io::Result<Self> {
+        let quotas = opts
+            .quota_limits
+            .iter()
+            .map(|(&uid, &limit)| (uid, Quota { used_blocks: 0, limit: Some(limit) }))
+            .collect();
+        let manifest = Manifest::load(&*backend, &backing)?;
         let mut st = Self {
+            backing,
+            backend,
             next_ino: 2,
+            next_block: 0,
             path_map: HashMap::new(),
             nodes: HashMap::new(),
             handles: HashMap::new(),
+            dir_handles: HashMap::new(),
+            next_fh: 1,
+            opts,
+            trashed: HashMap::new(),
+            quotas,
+            encode_stats: EncodeStats::default(),
+            io_retries: 0,
+            dirty_limit_hits: 0,
+            forced_flushes: 0,
+            pending_deletes: Vec::new(),
         };
-        let root = FileNode::new(1, "/", true, 0o755);
+        // The root belongs to whoever mounted the filesystem.
+        let mut root = FileNode::new(1, "/", true, 0o755);
+        root.uid = unsafe { libc::getuid() };
+        root.gid = unsafe { libc::getgid() };
         st.path_map.insert("/".to_string(), 1);
         st.nodes.insert(1, root);
-        st
-    }
-
-    fn alloc_ino(&mut self) -> Inode {
-        let ino = self.next_ino;
-        self.next_ino += 1;
-        ino
+        if let Some(m) = manifest {
+            st.restore(m);
+        }
+        Ok(st)
     }
 
-    fn make_full(parent: Inode, parent_name: &str, name: &str) -> String {
-        if parent == 1 {
-            format!("/{}", name)
-        } else {
-            format!("{}/{}", parent_name, name)
+    /// Rebuild the tree from a loaded manifest.
+    ///
+    /// Inode and block counters resume past the highest values seen, and
+    /// block images that no longer exist are marked so reads fail with EIO
+    /// instead of returning zeros.
+    fn restore(&mut self, m: Manifest) {
+        self.next_ino = self.next_ino.max(m.next_ino);
+        self.next_block = self.next_block.max(m.next_block);
+        // A crash after the last save may have left some of these behind.
+        for name in &m.pending_deletes {
+            let _ = self.backend.delete(&self.backing.join(name));
+        }
+        let mut missing = 0usize;
+        let mut collisions = 0usize;
+        for mn in m.nodes {
+            let mut node = FileNode::new(mn.ino, &mn.path, mn.is_dir, mn.perm);
+            node.size = mn.size;
+            node.uid = mn.uid;
+            node.gid = mn.gid;
+            node.atime = mn.atime;
+            node.mtime = mn.mtime;
+            node.ctime = mn.ctime;
+            // Manifests written before crtime was tracked only have ctime.
+            node.crtime = mn.crtime.unwrap_or(mn.ctime);
+            for (idx, name) in mn.blocks.iter().enumerate() {
+                if name.is_empty() {
+                    continue;
+                }
+                if let Some(id) = block_id(name) {
+                    self.next_block = self.next_block.max(id + 1);
+                }
+                let path = self.backing.join(name);
+                if !self.backend.exists(&path) {
+                    node.missing.insert(idx);
+                    missing += 1;
+                }
+                node.blocks.insert(idx, path);
+            }
+            if let Some(at) = mn.trashed_at {
+                self.trashed.insert(mn.ino, at);
+            }
+            self.quotas.entry(node.uid).or_default().used_blocks += node.blocks.len() as u64;
+            self.next_ino = self.next_ino.max(mn.ino + 1);
+            let key = self.path_key(&mn.path);
+            if let Some(&other) = self.path_map.get(&key).filter(|&&i| i != mn.ino) {
+                collisions += 1;
+                eprintln!(
+                    "imgfs: {} hides {} once names are folded",
+                    mn.path, self.nodes.get(&other).map_or("?", |n| n.name.as_str())
+                );
+            }
+            self.path_map.insert(key, mn.ino);
+            self.nodes.insert(mn.ino, node);
+        }
+        let dirs: Vec<String> = self
+            .nodes
+            .values()
+            .filter(|n| n.is_dir && n.ino != 1)
+            .map(|n| n.name.clone())
+            .collect();
+        for full in dirs {
+            self.link_parent(&full, 1);
+        }
+        if collisions > 0 {
+            eprintln!("imgfs: {} names collide under --casefold/--normalize", collisions);
+        }
+        if missing > 0 {
+            eprintln!("imgfs: {} block images listed in the manifest are missing", missing);
         }
     }
-}
 
-struct ImageFS {
-    state: Arc<Mutex<FilesystemState>>,
-}
-
-impl ImageFS {
-    fn new(backing: PathBuf) -> Self {
-        Self { state: Arc::new(Mutex::new(FilesystemState::new(backing))) }
+    fn to_manifest(&self) -> Manifest {
+        let nodes = self
+            .nodes
+            .values()
+            .map(|n| ManifestNode {
+                ino: n.ino,
+                path: n.name.clone(),
+                is_dir: n.is_dir,
+                size: n.size,
+                perm: n.perm,
+                uid: n.uid,
+                gid: n.gid,
+                atime: n.atime,
+                mtime: n.mtime,
+                ctime: n.ctime,
+                crtime: Some(n.crtime),
+                blocks: n.manifest_blocks(),
+                trashed_at: self.trashed.get(&n.ino).copied(),
+            })
+            .collect();
+        Manifest {
+            version: manifest::MANIFEST_VERSION,
+            next_ino: self.next_ino,
+            next_block: self.next_block,
+            nodes,
+            pending_deletes: self
+                .pending_deletes
+                .iter()
+                .map(|p| p.file_name().unwrap_or_default().to_string_lossy().into_owned())
+                .collect(),
+        }
     }
 
-    fn load_block_from_path(path: &Path) -> io::Result<Vec<u8>> {
-        if !path.exists() {
-            return Ok(vec![0u8; BLOCK_BYTES]);
+    /// Save the manifest, then delete the block images it let go of.
+    fn save_manifest(&mut self) -> io::Result<()> {
+        // A read-only store is mounted read-only, so nothing worth saving
+        // can have changed.
+        if self.backend.read_only() {
+            return Ok(());
         }
-        let bytes = std::fs::read(path)?;
-        let img = image::load_from_memory(&bytes).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        let gray = img.to_luma8();
-        let mut out = vec![0u8; BLOCK_BYTES];
-        let w = gray.width() as usize;
-        let h = gray.height() as usize;
-        for y in 0..BLOCK_H {
-            for x in 0..BLOCK_W {
-                let idx = y * BLOCK_W + x;
-                if x < w && y < h {
-                    out[idx] = gray.get_pixel(x as u32, y as u32)[0];
-                } else {
-                    out[idx] = 0;
-                }
-            }
+        self.to_manifest().save(&*self.backend, &self.backing)?;
+        for p in self.pending_deletes.drain(..) {
+            let _ = self.backend.delete(&p);
         }
-        Ok(out)
+        Ok(())
     }
 
-    fn save_block_to_path(path: &Path, buf: &[u8]) -> io::Result<()> {
-        assert_eq!(buf.len(), BLOCK_BYTES);
-        let mut imgbuf: GrayImage = ImageBuffer::new(BLOCK_W as u32, BLOCK_H as u32);
-        for y in 0..BLOCK_H {
-            for x in 0..BLOCK_W {
-                let value = buf[y * BLOCK_W + x];
-                imgbuf.put_pixel(x as u32, y as u32, Luma([value]));
+    /// Replace the manifest, first writing back all dirty data in
+    /// `DataMode::Ordered`. EIO if either step fails.
+    fn commit_manifest(&mut self) -> Result<(), c_int> {
+        if self.opts.data_mode == DataMode::Ordered {
+            let dirty: Vec<Inode> = self
+                .nodes
+                .values()
+                .filter(|n| !n.dirty.is_empty())
+                .map(|n| n.ino)
+                .collect();
+            for ino in dirty {
+                self.sync_node(ino, SyncMode::All)?;
             }
         }
-        if let Some(p) = path.parent() {
-            std::fs::create_dir_all(p)?;
-        }
-        imgbuf.save(path).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.save_manifest().map_err(|e| {
+            eprintln!("imgfs: cannot save manifest: {}", e);
+            libc::EIO
+        })
     }
 
-    pub fn ensure_blocks_for_size(node: &mut FileNode, new_size: u64) {
-        let needed_blocks =
-            ((new_size + BLOCK_BYTES as u64 - 1) / BLOCK_BYTES as u64) as usize;
-
-        while node.blocks.len() < needed_blocks {
-            let new_block = Self::alloc_block_path();
-            node.blocks.push(new_block.into());
+    /// Write back every dirty block, whatever the data mode, and then the
+    /// manifest. EIO if anything fails.
+    fn sync_all(&mut self) -> Result<(), c_int> {
+        let dirty: Vec<Inode> = self.nodes.values().filter(|n| !n.dirty.is_empty()).map(|n| n.ino).collect();
+        for ino in dirty {
+            self.sync_node(ino, SyncMode::All)?;
         }
+        self.commit_manifest()
     }
 
-    pub fn alloc_block_path() -> String {
-        use std::sync::atomic::{AtomicU64, Ordering};
-        static COUNTER: AtomicU64 = AtomicU64::new(0);
+    /// Open handles that allow writing, and bytes of dirty block data.
+    fn pending_writes(&self) -> (usize, u64) {
+        let handles = self
+            .handles
+            .values()
+            .filter(|&&(_, flags)| flags & libc::O_ACCMODE != libc::O_RDONLY)
+            .count();
+        let dirty: usize = self.nodes.values().map(|n| n.dirty.len()).sum();
+        (handles, (dirty * BLOCK_BYTES) as u64)
+    }
 
-        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
-        format!("block_{id}.png")
+    /// Path for a fresh block image in the backing directory.
+    fn alloc_block_path(&mut self) -> PathBuf {
+        let id = self.next_block;
+        self.next_block += 1;
+        self.backing.join(format!("block_{id}.png"))
     }
-}
 
-impl Filesystem for ImageFS {
-    fn getattr(&mut self, _req: &Request<'_>, ino: Inode, _fh: Option<u64>, reply: ReplyAttr) {
-        let st = self.state.lock().unwrap();
-        match st.nodes.get(&ino) {
-            Some(node) => reply.attr(&TTL, &node.attr()),
-            None => reply.error(ENOENT),
+    /// Account a successful read or write of `bytes` on `ino` by `uid`.
+    /// Callers check `opts.io_stats` first.
+    fn record_io(&mut self, ino: Inode, uid: u32, write: bool, bytes: usize) {
+        let audit = self.opts.audit;
+        if let Some(n) = self.nodes.get_mut(&ino) {
+            let s = &mut n.io_stats;
+            if write {
+                s.writes += 1;
+                s.bytes_written += bytes as u64;
+            } else {
+                s.reads += 1;
+                s.bytes_read += bytes as u64;
+            }
+            if audit {
+                s.last_uid = Some(uid);
+            }
         }
     }
 
-    fn setattr(
-        &mut self,
-        _req: &fuser::Request<'_>,
-        ino: u64,
-        mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        atime: Option<fuser::TimeOrNow>,
-        mtime: Option<fuser::TimeOrNow>,
-        _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<std::time::SystemTime>,
-        _chgtime: Option<std::time::SystemTime>,
-        _bkuptime: Option<std::time::SystemTime>,
-        _flags: Option<u32>,
-        reply: fuser::ReplyAttr,
-    ) {
-        let mut st = self.state.lock().unwrap();
+    /// Register a new handle on `ino` opened with `flags`.
+    fn open_handle(&mut self, ino: Inode, flags: i32) -> FH {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(fh, (ino, flags));
+        fh
+    }
 
-        let node = match st.nodes.get_mut(&ino) {
-            Some(n) => n,
-            None => { reply.error(libc::ENOENT); return; }
-        };
+    /// Register a new handle on directory `ino`, snapshotting its listing.
+    fn open_dir_handle(&mut self, ino: Inode) -> Result<FH, c_int> {
+        let listing = self.dir_listing(ino)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.dir_handles.insert(fh, listing);
+        Ok(fh)
+    }
 
-        let now = std::time::SystemTime::now();
+    /// Entries of directory handle `fh` from `offset` on, each with the
+    /// offset to continue after it: that of the entry that follows. EBADF
+    /// for a handle that is not open.
+    fn read_dir_handle(&self, fh: FH, offset: i64) -> Result<impl Iterator<Item = (i64, &DirEntry)>, c_int> {
+        let listing = self.dir_handles.get(&fh).ok_or(libc::EBADF)?;
+        Ok(listing.iter().enumerate().skip(offset.max(0) as usize).map(|(i, e)| (i as i64 + 1, e)))
+    }
+
+    /// Entries of directory `ino`: `.` and `..`, then its children sorted
+    /// by name.
+    ///
+    /// The order only depends on the names present, so offsets into the
+    /// listing stay meaningful across calls. Each directory handle keeps
+    /// the listing it was opened with, so entries created or removed
+    /// while a listing is read can neither skip nor repeat others.
+    fn dir_listing(&self, ino: Inode) -> Result<Vec<DirEntry>, c_int> {
+        let dir = self.dir_path(ino)?;
+        let parent = if ino == 1 { 1 } else { self.lookup_path(parent_of(&dir)).unwrap_or(1) };
+        let children: BTreeMap<&str, (Inode, FileType)> = self
+            .nodes
+            .values()
+            .filter(|n| n.ino != 1 && parent_of(&n.name) == dir)
+            .map(|n| {
+                let base = &n.name[n.name.rfind('/').map_or(0, |i| i + 1)..];
+                let kind = if n.is_dir { FileType::Directory } else { FileType::RegularFile };
+                (base, (n.ino, kind))
+            })
+            .collect();
+        let mut listing = vec![
+            DirEntry { ino, kind: FileType::Directory, name: ".".to_string() },
+            DirEntry { ino: parent, kind: FileType::Directory, name: "..".to_string() },
+        ];
+        listing.extend(children.into_iter().map(|(name, (ino, kind))| DirEntry { ino, kind, name: name.to_string() }));
+        Ok(listing)
+    }
 
-        if let Some(fuser::TimeOrNow::Now) | None = atime {
-            node.atime = now;
+    /// Inode an operation on handle `fh` acts on: EBADF for a handle that
+    /// is not open. The handle's own inode wins over `ino` if they differ.
+    ///
+    /// Inode numbers are never reused, so a handle cannot outlive its
+    /// file into a new one and there is no generation to check. A handle
+    /// whose file was freed, by unlink or by a rename over it, gets
+    /// ESTALE, even when a new file has taken the name since.
+    fn resolve_handle(&self, fh: FH, ino: Inode) -> Result<Inode, c_int> {
+        match self.handles.get(&fh) {
+            None => Err(libc::EBADF),
+            Some(&(owner, _)) if !self.nodes.contains_key(&owner) => Err(libc::ESTALE),
+            Some(&(owner, _)) => {
+                if owner != ino {
+                    eprintln!("imgfs: handle {} belongs to inode {}, not {}", fh, owner, ino);
+                }
+                Ok(owner)
+            }
         }
-        if let Some(fuser::TimeOrNow::SpecificTime(t)) = atime {
-            node.atime = t;
+    }
+
+    /// Whether `fh` was opened with O_DIRECT.
+    fn is_direct(&self, fh: FH) -> bool {
+        self.handles.get(&fh).is_some_and(|&(_, flags)| flags & libc::O_DIRECT != 0)
+    }
+
+    fn alloc_ino(&mut self) -> Inode {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        ino
+    }
+
+    /// Check `mask` on `ino` for `caller`; ENOENT if the node is missing.
+    fn check_access(&self, ino: Inode, caller: Caller, mask: i32) -> Result<(), c_int> {
+        match self.nodes.get(&ino) {
+            Some(n) => n.check_access(caller, mask),
+            None => Err(ENOENT),
         }
+    }
 
-        if let Some(fuser::TimeOrNow::Now) | None = mtime {
-            node.mtime = now;
+    /// Full path of directory `parent`: ENOENT if missing, ENOTDIR if a file.
+    fn dir_path(&self, parent: Inode) -> Result<String, c_int> {
+        match self.nodes.get(&parent) {
+            Some(n) if n.is_dir => Ok(n.name.clone()),
+            Some(_) => Err(ENOTDIR),
+            None => Err(ENOENT),
         }
-        if let Some(fuser::TimeOrNow::SpecificTime(t)) = mtime {
-            node.mtime = t;
+    }
+
+    /// Key under which `full` is stored in `path_map`.
+    ///
+    /// With `--normalize=nfc` the path is NFC-normalized, so names stored
+    /// before the option was enabled are still found. With `--casefold` it
+    /// is also lowercased, so names differing only in case find the same
+    /// node; the node keeps the original case.
+    fn path_key(&self, full: &str) -> String {
+        let key: String = match self.opts.normalize {
+            Some(NameForm::Nfc) => full.nfc().collect(),
+            None => full.to_string(),
+        };
+        if self.opts.casefold {
+            key.to_lowercase()
+        } else {
+            key
         }
+    }
 
-        if let Some(new_mode) = mode {
-            node.mode = new_mode;
+    /// Name of a new or looked-up entry as it is stored.
+    ///
+    /// Only valid UTF-8 names are normalized; anything else is left as is.
+    /// ENAMETOOLONG if the stored name exceeds `NAME_MAX` bytes, which every
+    /// handler taking a name relies on.
+    fn entry_name(&self, name: &OsStr) -> Result<String, c_int> {
+        let name = match (self.opts.normalize, name.to_str()) {
+            (Some(NameForm::Nfc), Some(s)) => s.nfc().collect(),
+            _ => name.to_string_lossy().into_owned(),
+        };
+        if name.len() > NAME_MAX {
+            return Err(ENAMETOOLONG);
         }
+        Ok(name)
+    }
 
-        reply.attr(&std::time::Duration::from_secs(1), &node.attr());
+    /// Node at full path `full`, if any.
+    fn lookup_path(&self, full: &str) -> Option<Inode> {
+        self.path_map.get(&self.path_key(full)).copied()
     }
 
-    fn lookup(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        reply: ReplyEntry,
-    ) {
-        let st = self.state.lock().unwrap();
+    /// Record a change to the entries of directory `ino`: creating,
+    /// removing or renaming an entry updates its mtime and ctime, which
+    /// build tools compare against stamp files.
+    fn touch_dir(&mut self, ino: Inode) {
+        if let Some(n) = self.nodes.get_mut(&ino) {
+            let now = SystemTime::now();
+            n.mtime = now;
+            n.ctime = now;
+        }
+    }
 
-        let parent_node = match st.nodes.get(&parent) {
-            Some(n) if n.is_dir => n,
-            _ => {
-                reply.error(ENOENT);
-                return;
+    /// Add `delta` to the subdirectory count of the directory holding `full`.
+    fn link_parent(&mut self, full: &str, delta: i32) {
+        if let Some(ino) = self.lookup_path(parent_of(full)) {
+            if let Some(n) = self.nodes.get_mut(&ino) {
+                n.subdirs = n.subdirs.saturating_add_signed(delta);
             }
-        };
+        }
+    }
 
-        let name_str = name.to_string_lossy();
-        let full = FilesystemState::make_full(parent, &parent_node.name, &name_str);
+    fn make_full(parent: Inode, parent_name: &str, name: &str) -> String {
+        if parent == 1 {
+            format!("/{}", name)
+        } else {
+            format!("{}/{}", parent_name, name)
+        }
+    }
 
-        let ino = match st.path_map.get(&full) {
-            Some(&i) => i,
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
+    /// Largest file size `uid` may create: `--max-file-size`, and no more
+    /// than its whole block quota.
+    fn max_file_size(&self, uid: u32) -> u64 {
+        // A no-op on 64-bit targets, where the limit saturates.
+        #[allow(clippy::unnecessary_min_or_max)]
+        let max = self.opts.max_file_size.min(MAX_INDEXED_FILE_SIZE);
+        match self.quotas.get(&uid) {
+            Some(Quota { limit: Some(limit), .. }) => max.min(limit.saturating_mul(BLOCK_BYTES as u64)),
+            _ => max,
+        }
+    }
 
-        let node = match st.nodes.get(&ino) {
-            Some(n) => n,
-            None => {
-                reply.error(ENOENT);
-                return;
+    /// Charge `blocks` newly allocated blocks to `uid`.
+    ///
+    /// Fails with EDQUOT, charging nothing, if that would exceed the limit.
+    fn charge_blocks(&mut self, uid: u32, blocks: u64) -> Result<(), c_int> {
+        let q = self.quotas.entry(uid).or_default();
+        if let Some(limit) = q.limit {
+            if q.used_blocks + blocks > limit {
+                return Err(EDQUOT);
             }
-        };
+        }
+        q.used_blocks += blocks;
+        Ok(())
+    }
 
-        reply.entry(&TTL, &node.attr(), 0);
+    fn release_blocks(&mut self, uid: u32, blocks: u64) {
+        if let Some(q) = self.quotas.get_mut(&uid) {
+            q.used_blocks = q.used_blocks.saturating_sub(blocks);
+        }
     }
 
-    fn create(
+    fn over_quota(&self, uid: u32) -> bool {
+        match self.quotas.get(&uid) {
+            Some(Quota { used_blocks, limit: Some(limit) }) => used_blocks >= limit,
+            _ => false,
+        }
+    }
+
+    /// Drop a node from the tree; its block images go once the manifest
+    /// no longer lists them.
+    fn free_node(&mut self, ino: Inode) {
+        if let Some(node) = self.nodes.remove(&ino) {
+            let key = self.path_key(&node.name);
+            self.path_map.remove(&key);
+            self.release_blocks(node.uid, node.blocks.len() as u64);
+            self.pending_deletes.extend(node.blocks.into_values());
+        }
+        self.trashed.remove(&ino);
+    }
+
+    /// Inode of the trash directory, created on first use.
+    fn trash_dir(&mut self) -> Inode {
+        if let Some(ino) = self.lookup_path(TRASH_DIR) {
+            return ino;
+        }
+        let ino = self.alloc_ino();
+        self.path_map.insert(self.path_key(TRASH_DIR), ino);
+        let mut trash = FileNode::new(ino, TRASH_DIR, true, 0o700);
+        trash.uid = self.nodes[&1].uid;
+        trash.gid = self.nodes[&1].gid;
+        self.nodes.insert(ino, trash);
+        self.link_parent(TRASH_DIR, 1);
+        ino
+    }
+
+    /// Re-link `full` into the trash as `<unix secs>.<ino>.<name>`.
+    ///
+    /// The inode and its blocks stay untouched, so the file can be
+    /// restored with a plain `mv` until the reaper expires it.
+    fn move_to_trash(&mut self, ino: Inode, full: &str) {
+        let trash = self.trash_dir();
+        self.touch_dir(trash);
+        let now = SystemTime::now();
+        let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let base = full.rsplit('/').next().unwrap_or(full);
+        let trashed = format!("{}/{}.{}.{}", TRASH_DIR, secs, ino, base);
+
+        self.path_map.remove(&self.path_key(full));
+        self.path_map.insert(self.path_key(&trashed), ino);
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.name = trashed;
+            node.ctime = now;
+        }
+        self.trashed.insert(ino, now);
+    }
+
+    /// Free every trashed file older than the retention period.
+    ///
+    /// Returns the trash directory and the names removed from it, so the
+    /// kernel's cached entries can be invalidated.
+    fn expire_trash(&mut self) -> (Inode, Vec<String>) {
+        self.free_trash(self.opts.trash_retention)
+    }
+
+    /// Free every trashed file right away, for `EMPTY_TRASH_XATTR` set on
+    /// the trash directory `ino`. Only root and the owner of the trash may.
+    fn empty_trash(&mut self, ino: Inode, (uid, _): Caller) -> Result<(), c_int> {
+        let trash = self.lookup_path(TRASH_DIR).filter(|&t| t == ino).ok_or(libc::ENOTSUP)?;
+        if uid != 0 && uid != self.nodes[&trash].uid {
+            return Err(EPERM);
+        }
+        self.free_trash(Duration::ZERO);
+        self.touch_dir(trash);
+        Ok(())
+    }
+
+    /// Free the trashed files that have been in the trash for at least
+    /// `retention`; see `expire_trash`.
+    fn free_trash(&mut self, retention: Duration) -> (Inode, Vec<String>) {
+        let expired: Vec<Inode> = self
+            .trashed
+            .iter()
+            .filter(|(_, at)| at.elapsed().map(|age| age >= retention).unwrap_or(false))
+            .map(|(&ino, _)| ino)
+            .collect();
+        let mut names = Vec::new();
+        for ino in expired {
+            if let Some(n) = self.nodes.get(&ino) {
+                names.push(n.name.rsplit('/').next().unwrap_or_default().to_string());
+            }
+            self.free_node(ino);
+        }
+        (self.lookup_path(TRASH_DIR).unwrap_or(1), names)
+    }
+
+    /// Delete block images in the backing directory that no node uses.
+    ///
+    /// Such orphans are left behind when the daemon dies after writing a
+    /// block but before the manifest that references it. Trashed files
+    /// still own their blocks and are kept. Images smaller than `min_len`
+    /// bytes are skipped. Returns the number of bytes released.
+    fn trim_orphans(&mut self, min_len: u64) -> io::Result<u64> {
+        let mut trimmed = 0;
+        for (path, len) in self.orphan_blocks()? {
+            if len >= min_len {
+                self.backend.delete(&path)?;
+                trimmed += len;
+            }
+        }
+        Ok(trimmed)
+    }
+
+    /// Block images in the backing directory that no node references,
+    /// with their sizes. Blocks waiting in `pending_deletes` are not
+    /// orphans: the saved manifest still names them until the next save.
+    fn orphan_blocks(&self) -> io::Result<Vec<(PathBuf, u64)>> {
+        let used: HashSet<&PathBuf> =
+            self.nodes.values().flat_map(|n| n.blocks.values()).chain(&self.pending_deletes).collect();
+        let mut orphans = self.backend.list()?;
+        orphans.retain(|(path, _)| {
+            let is_block = path.file_name().and_then(|n| n.to_str()).and_then(block_id).is_some();
+            is_block && !used.contains(path)
+        });
+        Ok(orphans)
+    }
+
+    /// `--gc`: move orphaned block images into `LOST_FOUND_DIR` of the
+    /// backing directory, or delete them. Returns how many images were
+    /// handled and their total size.
+    fn collect_orphans(&mut self, mode: GcMode) -> io::Result<(u64, u64)> {
+        let orphans = self.orphan_blocks()?;
+        let lost_found = self.backing.join(LOST_FOUND_DIR);
+        let (mut count, mut bytes) = (0, 0);
+        for (path, len) in orphans {
+            match mode {
+                GcMode::Delete => self.backend.delete(&path)?,
+                GcMode::Move => {
+                    // Block ids are reused after a counter reset, so an
+                    // earlier GC may have kept an image of the same name.
+                    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                    let mut target = lost_found.join(&name);
+                    let mut n = 1;
+                    while self.backend.exists(&target) {
+                        target = lost_found.join(format!("{}.{}", name, n));
+                        n += 1;
+                    }
+                    self.backend.rename(&path, &target)?;
+                }
+            }
+            count += 1;
+            bytes += len;
+        }
+        Ok((count, bytes))
+    }
+
+    /// Create a node called `name` under `parent`, owned by `uid`/`gid`.
+    fn create_node(
         &mut self,
-        _req: &Request<'_>,
         parent: Inode,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        flags: i32,
-        reply: ReplyCreate,
-    ) {
-        let mut st = self.state.lock().unwrap();
-        let parent_node = match st.nodes.get(&parent) {
-            Some(n) if n.is_dir => n.clone(),
-            _ => { reply.error(ENOENT); return; }
-        };
-        let name_str = name.to_string_lossy();
-        let full = FilesystemState::make_full(parent, &parent_node.name, &name_str);
-        if st.path_map.contains_key(&full) {
-            reply.error(EEXIST);
-            return;
+        is_dir: bool,
+        perm: u32,
+        (uid, caller_gid): Caller,
+    ) -> Result<Inode, c_int> {
+        let parent_name = self.dir_path(parent)?;
+        self.check_access(parent, (uid, caller_gid), W_OK | X_OK)?;
+        let full = Self::make_full(parent, &parent_name, &self.entry_name(name)?);
+        check_path_limits(&full)?;
+        if self.lookup_path(&full).is_some() {
+            return Err(EEXIST);
+        }
+        if !is_dir && self.over_quota(uid) {
+            return Err(EDQUOT);
+        }
+        // As on ext4: a setgid directory hands its group down, and its
+        // setgid bit to subdirectories. A file only keeps a requested
+        // setgid bit if its creator is in the group it ends up with.
+        let parent_node = &self.nodes[&parent];
+        let (gid, perm) = if parent_node.perm & 0o2000 != 0 {
+            let perm = if is_dir { perm | 0o2000 } else { perm };
+            (parent_node.gid, perm)
+        } else {
+            (caller_gid, perm)
+        };
+        let perm = if !is_dir && uid != 0 && gid != caller_gid { perm & !0o2000 } else { perm };
+        let ino = self.alloc_ino();
+        let mut node = FileNode::new(ino, &full, is_dir, perm);
+        node.uid = uid;
+        node.gid = gid;
+        self.path_map.insert(self.path_key(&full), ino);
+        self.nodes.insert(ino, node);
+        if is_dir {
+            self.link_parent(&full, 1);
         }
-        let ino = st.alloc_ino();
-        let mut node = FileNode::new(ino, &full, false, 0o644);
-        node.size = 0;
-        st.path_map.insert(full.clone(), ino);
-        st.nodes.insert(ino, node);
-        // create a simple fh
-        let fh = ino; // simple mapping
-        st.handles.insert(fh, (ino, flags));
-        let created = st.nodes.get(&ino).unwrap().clone();
-        reply.created(&TTL, &created.attr(), 0, fh, flags as u32);
+        self.touch_dir(parent);
+        Ok(ino)
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: Inode, flags: i32, reply: ReplyOpen) {
-        let mut st = self.state.lock().unwrap();
-        if !st.nodes.contains_key(&ino) {
-            reply.error(ENOENT);
-            return;
+    fn unlink(&mut self, parent: Inode, name: &OsStr, caller: Caller) -> Result<(), c_int> {
+        let parent_name = self.dir_path(parent)?;
+        self.check_access(parent, caller, W_OK | X_OK)?;
+        let full = Self::make_full(parent, &parent_name, &self.entry_name(name)?);
+        let ino = match self.lookup_path(&full) {
+            Some(i) => i,
+            None => return Err(ENOENT),
+        };
+        if self.nodes.get(&ino).is_some_and(|n| n.is_dir) {
+            return Err(EPERM);
+        }
+        // Unlinking from inside the trash really deletes.
+        if self.opts.trash && parent_name != TRASH_DIR {
+            self.move_to_trash(ino, &full);
+        } else {
+            self.free_node(ino);
         }
-        let fh = ino + 1000;
-        st.handles.insert(fh, (ino, flags));
-        reply.opened(fh, flags as u32);
+        self.touch_dir(parent);
+        Ok(())
     }
 
-    fn read(
+    /// Remove the empty directory `name`: ENOTDIR for a file, ENOTEMPTY
+    /// while any entry is left under it.
+    fn rmdir(&mut self, parent: Inode, name: &OsStr, caller: Caller) -> Result<(), c_int> {
+        let parent_name = self.dir_path(parent)?;
+        self.check_access(parent, caller, W_OK | X_OK)?;
+        let full = Self::make_full(parent, &parent_name, &self.entry_name(name)?);
+        let ino = match self.lookup_path(&full) {
+            Some(i) => i,
+            None => return Err(ENOENT),
+        };
+        if !self.nodes.get(&ino).is_some_and(|n| n.is_dir) {
+            return Err(ENOTDIR);
+        }
+        let prefix = self.path_key(&format!("{}/", full));
+        if self.path_map.keys().any(|k| k.starts_with(&prefix)) {
+            return Err(libc::ENOTEMPTY);
+        }
+        self.free_node(ino);
+        self.link_parent(&full, -1);
+        self.touch_dir(parent);
+        Ok(())
+    }
+
+    fn rename(
         &mut self,
-        _req: &Request<'_>,
-        ino: Inode,
-        _fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let mut st = self.state.lock().unwrap();
-        let node = match st.nodes.get_mut(&ino) {
+        parent: Inode,
+        name: &OsStr,
+        newparent: Inode,
+        newname: &OsStr,
+        caller: Caller,
+    ) -> Result<(), c_int> {
+        let parent_name = self.dir_path(parent)?;
+        let new_parent_name = self.dir_path(newparent)?;
+        self.check_access(parent, caller, W_OK | X_OK)?;
+        self.check_access(newparent, caller, W_OK | X_OK)?;
+        let old_full = Self::make_full(parent, &parent_name, &self.entry_name(name)?);
+        let new_full = Self::make_full(newparent, &new_parent_name, &self.entry_name(newname)?);
+        let ino = match self.lookup_path(&old_full) {
+            Some(i) => i,
+            None => return Err(ENOENT),
+        };
+        // Descendants are keyed by full path, so they move with a directory.
+        // Their names start with the stored name, whose case may differ
+        // from the one given under --casefold.
+        let stored_len = self.nodes[&ino].name.len();
+        let old_prefix = self.path_key(&format!("{}/", old_full));
+        if self.path_key(&new_full).starts_with(&old_prefix) {
+            return Err(EINVAL);
+        }
+        let moved: Vec<(String, Inode)> = self
+            .path_map
+            .iter()
+            .filter(|(k, _)| k.starts_with(&old_prefix))
+            .map(|(_, &i)| (format!("{}{}", new_full, &self.nodes[&i].name[stored_len..]), i))
+            .collect();
+        check_path_limits(&new_full)?;
+        for (name, _) in &moved {
+            check_path_limits(name)?;
+        }
+        // A file may not replace a directory and vice versa.
+        if let Some(target) = self.lookup_path(&new_full).and_then(|t| self.nodes.get(&t)) {
+            match (self.nodes[&ino].is_dir, target.is_dir) {
+                (false, true) => return Err(EISDIR),
+                (true, false) => return Err(ENOTDIR),
+                _ => {}
+            }
+        }
+        // Rename barrier: a file replacing another (write-tmp-then-rename)
+        // has its data written back before the rename and the manifest
+        // committed after it, so a crash leaves the target with either
+        // the complete old or the complete new content.
+        let barrier = self.opts.rename_barrier
+            && !self.nodes[&ino].is_dir
+            && self.lookup_path(&new_full).is_some_and(|t| t != ino);
+        if barrier {
+            self.sync_node(ino, SyncMode::All)?;
+        }
+        // The replaced entry goes away; a directory must be empty first.
+        if let Some(old) = self.lookup_path(&new_full) {
+            if old != ino {
+                let prefix = self.path_key(&format!("{}/", new_full));
+                if self.path_map.keys().any(|k| k.starts_with(&prefix)) {
+                    return Err(libc::ENOTEMPTY);
+                }
+                if self.nodes[&old].is_dir {
+                    self.link_parent(&new_full, -1);
+                }
+                self.free_node(old);
+            }
+        }
+        self.path_map.remove(&self.path_key(&old_full));
+        self.path_map.insert(self.path_key(&new_full), ino);
+        for (new_name, child) in moved {
+            let old_key = self.path_key(&self.nodes[&child].name);
+            self.path_map.remove(&old_key);
+            self.path_map.insert(self.path_key(&new_name), child);
+            if let Some(n) = self.nodes.get_mut(&child) {
+                n.name = new_name;
+            }
+        }
+        if self.nodes[&ino].is_dir {
+            self.link_parent(&old_full, -1);
+            self.link_parent(&new_full, 1);
+        }
+        // Moving a file out of the trash restores it, so the reaper must
+        // not free it any more.
+        if !self.path_key(&new_full).starts_with(&self.path_key(&format!("{}/", TRASH_DIR))) {
+            self.trashed.remove(&ino);
+        }
+        // The moved node's contents are unchanged, so only its ctime moves.
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.name = new_full;
+            node.ctime = SystemTime::now();
+        }
+        self.touch_dir(parent);
+        self.touch_dir(newparent);
+        if barrier {
+            // The rename has happened either way; a failed commit (already
+            // logged) only leaves it as undurable as any unsynced change.
+            let _ = self.commit_manifest();
+        }
+        Ok(())
+    }
+
+    /// Set the size of file `ino`.
+    ///
+    /// Blocks wholly past the new end are freed. When the new end falls
+    /// inside a block, the rest of that block is zeroed, so a file that
+    /// later grows again reads zeros there rather than the old contents.
+    fn truncate(&mut self, ino: Inode, size: u64) -> Result<(), c_int> {
+        if self.nodes.get(&ino).is_some_and(|n| size > self.max_file_size(n.uid)) {
+            return Err(libc::EFBIG);
+        }
+        let node = match self.nodes.get_mut(&ino) {
+            Some(n) if n.is_dir => return Err(EISDIR),
             Some(n) => n,
-            None => { reply.error(ENOENT); return; }
+            None => return Err(ENOENT),
         };
 
-        let off = offset as u64;
-        if off >= node.size {
-            reply.data(&[]);
-            return;
+        let keep = size.div_ceil(BLOCK_BYTES as u64) as usize;
+        let tail_off = (size % BLOCK_BYTES as u64) as usize;
+        // A tail that is a hole already reads as zeros.
+        let tail = keep.checked_sub(1).filter(|_| size < node.size && tail_off != 0);
+        if let Some((idx, path)) = tail.and_then(|i| node.blocks.get(&i).map(|p| (i, p))) {
+            if let Entry::Vacant(slot) = node.dirty.entry(idx) {
+                match ImageFS::load_block(&*self.backend, path) {
+                    Ok(buf) => { slot.insert(buf); }
+                    Err(e) => {
+                        eprintln!("imgfs: cannot load {}: {}", path.display(), e);
+                        return Err(retry::errno(&e));
+                    }
+                }
+            }
+            node.dirty.get_mut(&idx).unwrap()[tail_off..].fill(0);
+            node.dirty_at.entry(idx).or_insert_with(Instant::now);
+        }
+
+        let dropped = node.blocks.split_off(&keep);
+        node.dirty.retain(|&i, _| i < keep);
+        node.dirty_at.retain(|&i, _| i < keep);
+        node.saved_hashes.retain(|&i, _| i < keep);
+        node.missing.retain(|&i| i < keep);
+
+        let now = SystemTime::now();
+        node.size = size;
+        node.mtime = now;
+        node.ctime = now;
+        let uid = node.uid;
+        self.release_blocks(uid, dropped.len() as u64);
+        self.pending_deletes.extend(dropped.into_values());
+        Ok(())
+    }
+
+    /// Change the owner and/or group of `ino`.
+    ///
+    /// Only root gives a file away; its owner may only move it to their
+    /// own group. The file's blocks move to the new owner's quota, which
+    /// may fail with EDQUOT. As on ext4, a chown by anyone but root drops
+    /// the setuid and setgid bits of a file.
+    fn chown(&mut self, ino: Inode, uid: Option<u32>, gid: Option<u32>, (caller, caller_gid): Caller) -> Result<(), c_int> {
+        self.check_chown(ino, uid, gid, (caller, caller_gid))?;
+        let node = &self.nodes[&ino];
+        let (old_uid, blocks) = (node.uid, node.blocks.len() as u64);
+        if let Some(new_uid) = uid.filter(|&u| u != old_uid) {
+            self.charge_blocks(new_uid, blocks)?;
+            self.release_blocks(old_uid, blocks);
+        }
+        let node = self.nodes.get_mut(&ino).ok_or(ENOENT)?;
+        node.uid = uid.unwrap_or(node.uid);
+        node.gid = gid.unwrap_or(node.gid);
+        if caller != 0 && !node.is_dir {
+            node.perm &= !0o6000;
+        }
+        Ok(())
+    }
+
+    /// EPERM unless `caller` may make the changes `chown` would.
+    fn check_chown(&self, ino: Inode, uid: Option<u32>, gid: Option<u32>, (caller, caller_gid): Caller) -> Result<(), c_int> {
+        let node = self.nodes.get(&ino).ok_or(ENOENT)?;
+        let uid_ok = uid.is_none_or(|u| caller == 0 || u == node.uid);
+        let gid_ok =
+            gid.is_none_or(|g| caller == 0 || (caller == node.uid && (g == node.gid || g == caller_gid)));
+        if !uid_ok || !gid_ok {
+            return Err(EPERM);
+        }
+        Ok(())
+    }
+
+    /// Change the mode of `ino`.
+    ///
+    /// Only the owner or root may. As on Linux, the setgid bit is dropped
+    /// when a caller other than root is not in the file's group.
+    fn chmod(&mut self, ino: Inode, mode: u32, (caller, caller_gid): Caller) -> Result<(), c_int> {
+        self.check_chmod(ino, (caller, caller_gid))?;
+        let node = self.nodes.get_mut(&ino).ok_or(ENOENT)?;
+        let mode = if caller != 0 && caller_gid != node.gid { mode & !0o2000 } else { mode };
+        node.mode = mode;
+        node.perm = mode & 0o7777;
+        Ok(())
+    }
+
+    /// EPERM unless `caller` owns `ino` or is root.
+    fn check_chmod(&self, ino: Inode, (caller, _): Caller) -> Result<(), c_int> {
+        match self.nodes.get(&ino) {
+            None => Err(ENOENT),
+            Some(n) if caller != 0 && caller != n.uid => Err(EPERM),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Whether `caller` may truncate `ino`: write permission, unless `fh`
+    /// is a handle on it opened for writing, as with ftruncate(2), whose
+    /// permission was checked at open.
+    fn check_truncate(&self, ino: Inode, fh: Option<FH>, caller: Caller) -> Result<(), c_int> {
+        let writable = fh
+            .and_then(|fh| self.handles.get(&fh))
+            .is_some_and(|&(owner, flags)| owner == ino && flags & libc::O_ACCMODE != libc::O_RDONLY);
+        if writable && self.nodes.contains_key(&ino) {
+            return Ok(());
+        }
+        self.check_access(ino, caller, W_OK)
+    }
+
+    /// Set the times of `ino`; a time that is `None` (UTIME_OMIT) stays.
+    ///
+    /// As with utimensat(2), explicit times need the owner or root, while
+    /// setting both to now is also allowed with write permission.
+    fn set_times(
+        &mut self,
+        ino: Inode,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        crtime: Option<SystemTime>,
+        caller: Caller,
+        now: SystemTime,
+    ) -> Result<(), c_int> {
+        let node = self.nodes.get_mut(&ino).ok_or(ENOENT)?;
+        let explicit = crtime.is_some()
+            || [atime, mtime].iter().any(|t| matches!(t, Some(fuser::TimeOrNow::SpecificTime(_))));
+        if caller.0 != 0 && caller.0 != node.uid {
+            if explicit {
+                return Err(EPERM);
+            }
+            node.check_access(caller, W_OK)?;
+        }
+        let resolve = |t| match t {
+            fuser::TimeOrNow::SpecificTime(t) => t,
+            fuser::TimeOrNow::Now => now,
+        };
+        if let Some(t) = atime {
+            node.atime = resolve(t);
+        }
+        if let Some(t) = mtime {
+            node.mtime = resolve(t);
+        }
+        // Restore tools set the birth time explicitly.
+        if let Some(t) = crtime {
+            node.crtime = t;
+        }
+        Ok(())
+    }
+
+    /// Read up to `size` bytes of `ino` from `offset`; short at EOF.
+    fn read_at(&mut self, ino: Inode, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        let node = match self.nodes.get_mut(&ino) {
+            Some(n) if n.is_dir => return Err(EISDIR),
+            Some(n) => n,
+            None => return Err(ENOENT),
+        };
+        if offset >= node.size {
+            return Ok(Vec::new());
         }
-        let end = std::cmp::min(node.size, off + size as u64);
-        let mut out: Vec<u8> = Vec::with_capacity((end - off) as usize);
+        let end = std::cmp::min(node.size, offset + size as u64);
+        let mut out: Vec<u8> = Vec::with_capacity((end - offset) as usize);
 
-        let mut pos = off;
+        let mut pos = offset;
         while pos < end {
             let block_idx = (pos / (BLOCK_BYTES as u64)) as usize;
             let block_off = (pos % (BLOCK_BYTES as u64)) as usize;
             let to_read = std::cmp::min(end - pos, (BLOCK_BYTES - block_off) as u64) as usize;
 
-            if block_idx >= node.blocks.len() {
-                out.extend(std::iter::repeat(0u8).take(to_read));
+            if let Some(buf) = node.dirty.get(&block_idx) {
+                out.extend_from_slice(&buf[block_off..block_off + to_read]);
+            } else if node.missing.contains(&block_idx) {
+                return Err(libc::EIO);
             } else {
-                if let Some(buf) = node.dirty.get(&block_idx) {
-                    out.extend_from_slice(&buf[block_off..block_off + to_read]);
-                } else {
-                    match ImageFS::load_block_from_path(&node.blocks[block_idx]) {
+                match node.blocks.get(&block_idx) {
+                    None => out.extend(std::iter::repeat_n(0u8, to_read)),
+                    Some(path) => match ImageFS::load_block(&*self.backend, path) {
                         Ok(buf) => out.extend_from_slice(&buf[block_off..block_off + to_read]),
-                        Err(_) => out.extend(std::iter::repeat(0u8).take(to_read)),
-                    }
+                        Err(e) => {
+                            eprintln!("imgfs: cannot load {}: {}", path.display(), e);
+                            return Err(retry::errno(&e));
+                        }
+                    },
                 }
             }
             pos += to_read as u64;
         }
 
         node.atime = SystemTime::now();
-        reply.data(&out);
+        Ok(out)
     }
 
-    fn write(
-        &mut self,
-        _req: &Request<'_>,
-        ino: Inode,
-        _fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        let mut st = self.state.lock().unwrap();
-        let node = match st.nodes.get_mut(&ino) {
-            Some(n) => n,
-            None => { reply.error(ENOENT); return; }
+    /// Write `data` at `offset` into the node's dirty blocks.
+    fn write_at(&mut self, ino: Inode, offset: i64, data: &[u8]) -> Result<usize, c_int> {
+        let uid = match self.nodes.get(&ino) {
+            Some(n) if n.is_dir => return Err(EISDIR),
+            Some(n) => n.uid,
+            None => return Err(ENOENT),
         };
 
         let mut pos = offset as u64;
         let mut written = 0usize;
         let total = data.len();
+        if total == 0 {
+            return Ok(0);
+        }
+        let end = pos.checked_add(total as u64).ok_or(libc::EFBIG)?;
+        if end > self.max_file_size(uid) {
+            return Err(libc::EFBIG);
+        }
+
+        let first = (pos / BLOCK_BYTES as u64) as usize;
+        let last = ((end - 1) / BLOCK_BYTES as u64) as usize;
+        let newly_dirty = (first..=last).filter(|i| !self.nodes[&ino].dirty.contains_key(i)).count();
+        self.make_dirty_room(newly_dirty)?;
+
+        // Only the blocks this write touches are allocated; anything it
+        // skips over stays a hole.
+        let fresh: Vec<usize> = (first..=last).filter(|i| !self.nodes[&ino].blocks.contains_key(i)).collect();
+        self.charge_blocks(uid, fresh.len() as u64)?;
+        for idx in fresh {
+            let path = self.alloc_block_path();
+            self.nodes.get_mut(&ino).unwrap().blocks.insert(idx, path);
+        }
 
-        let final_size = std::cmp::max(node.size, pos + total as u64);
-        ImageFS::ensure_blocks_for_size(node, final_size);
+        let node = self.nodes.get_mut(&ino).unwrap();
 
         while written < total {
             let block_idx = (pos / (BLOCK_BYTES as u64)) as usize;
             let block_off = (pos % (BLOCK_BYTES as u64)) as usize;
             let to_write = std::cmp::min(total - written, BLOCK_BYTES - block_off);
 
-            let buf = node.dirty.entry(block_idx).or_insert_with(|| {
-                ImageFS::load_block_from_path(&node.blocks[block_idx]).unwrap_or_else(|_| vec![0u8; BLOCK_BYTES])
-            });
+            if !node.dirty.contains_key(&block_idx) {
+                let path = &node.blocks[&block_idx];
+                match ImageFS::load_block(&*self.backend, path) {
+                    Ok(buf) => { node.dirty.insert(block_idx, buf); }
+                    Err(e) => {
+                        eprintln!("imgfs: cannot load {}: {}", path.display(), e);
+                        // Report what was written so far as a short write.
+                        if written > 0 { break; }
+                        return Err(retry::errno(&e));
+                    }
+                }
+            }
+            let buf = node.dirty.get_mut(&block_idx).unwrap();
+            node.dirty_at.entry(block_idx).or_insert_with(Instant::now);
 
             buf[block_off..block_off + to_write].copy_from_slice(&data[written..written + to_write]);
 
@@ -411,173 +1680,2011 @@ impl Filesystem for ImageFS {
 
         node.size = std::cmp::max(node.size, offset as u64 + written as u64);
         node.mtime = SystemTime::now();
-        reply.written(written as u32);
+        node.write_amp.logical_bytes += written as u64;
+        self.encode_stats.amp.logical_bytes += written as u64;
+        Ok(written)
     }
 
-    fn rename(
-        &mut self,
-        _req: &Request<'_>,
-        parent: Inode,
-        name: &OsStr,
-        newparent: Inode,
-        newname: &OsStr,
-        _flags: u32,
-        reply: ReplyEmpty,
-    ) {
-        let mut st = self.state.lock().unwrap();
-        let parent_node = match st.nodes.get(&parent) {
-            Some(n) => n.clone(),
-            None => { reply.error(ENOENT); return; }
-        };
-        let new_parent_node = match st.nodes.get(&newparent) {
-            Some(n) => n.clone(),
-            None => { reply.error(ENOENT); return; }
-        };
-        let old_full = FilesystemState::make_full(parent, &parent_node.name, &name.to_string_lossy());
-        let new_full = FilesystemState::make_full(newparent, &new_parent_node.name, &newname.to_string_lossy());
-        let ino = match st.path_map.remove(&old_full) {
-            Some(i) => i,
-            None => { reply.error(ENOENT); return; }
-        };
-        st.path_map.insert(new_full.clone(), ino);
-        if let Some(node) = st.nodes.get_mut(&ino) {
-            node.name = new_full;
-            node.mtime = SystemTime::now();
+    /// Write back the least recently dirtied blocks until `needed` more
+    /// fit under `max_dirty_blocks`, so a writer outrunning the encoder
+    /// is slowed down instead of growing the dirty maps without bound.
+    ///
+    /// A single write larger than the limit still gets the blocks it
+    /// touches once everything else is written back.
+    fn make_dirty_room(&mut self, needed: usize) -> Result<(), c_int> {
+        if needed == 0 {
+            return Ok(());
         }
-        reply.ok();
-    }
-
-    fn mkdir(
-        &mut self,
-        _req: &Request<'_>,
-        parent: Inode,
-        name: &OsStr,
-        mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        let mut st = self.state.lock().unwrap();
-        let parent_node = match st.nodes.get(&parent) {
-            Some(n) if n.is_dir => n.clone(),
-            _ => { reply.error(ENOENT); return; }
-        };
-        let name_s = name.to_string_lossy();
-        let full = FilesystemState::make_full(parent, &parent_node.name, &name_s);
-        if st.path_map.contains_key(&full) {
-            reply.error(EEXIST);
-            return;
+        let mut dirty: usize = self.nodes.values().map(|n| n.dirty.len()).sum();
+        if dirty + needed <= self.opts.max_dirty_blocks {
+            return Ok(());
+        }
+        self.dirty_limit_hits += 1;
+        while dirty + needed > self.opts.max_dirty_blocks {
+            let oldest = self
+                .nodes
+                .values()
+                .flat_map(|n| n.dirty_at.iter().map(move |(&idx, &t)| (t, n.ino, idx)))
+                .min();
+            let Some((_, ino, idx)) = oldest else { break };
+            self.sync_node(ino, SyncMode::Range(idx, idx))?;
+            self.forced_flushes += 1;
+            dirty -= 1;
         }
-        let ino = st.alloc_ino();
-        let node = FileNode::new(ino, &full, true, mode);
-        st.path_map.insert(full.clone(), ino);
-        st.nodes.insert(ino, node);
-        let n = st.nodes.get(&ino).unwrap().clone();
-        reply.entry(&TTL, &n.attr(), 0);
+        Ok(())
     }
 
-    fn statfs(&mut self, _req: &Request<'_>, _ino: Inode, reply: ReplyStatfs) {
-        let st = self.state.lock().unwrap();
-        let blocks = 1_000_000u64;
-        reply.statfs(
-            blocks,
-            blocks / 2,
-            blocks / 2,
-            st.nodes.len() as u64,
-            0,
-            BLOCK_BYTES as u32,
-            255,
-            0,
-        );
+    /// Write through an O_DIRECT handle: the touched blocks are encoded
+    /// before the write returns. EINVAL if `offset` or the length is not
+    /// a multiple of `DIRECT_IO_ALIGN`.
+    fn write_direct(&mut self, ino: Inode, offset: i64, data: &[u8]) -> Result<usize, c_int> {
+        if !direct_aligned(offset, data.len() as u64) {
+            return Err(EINVAL);
+        }
+        let written = self.write_at(ino, offset, data)?;
+        if written > 0 {
+            let first = (offset as u64 / BLOCK_BYTES as u64) as usize;
+            let last = ((offset as u64 + written as u64 - 1) / BLOCK_BYTES as u64) as usize;
+            self.sync_node(ino, SyncMode::Range(first, last))?;
+        }
+        Ok(written)
     }
 
-    fn fsync(&mut self, _req: &Request<'_>, ino: Inode, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        let mut st = self.state.lock().unwrap();
-        let node = match st.nodes.get_mut(&ino) {
+    /// Encode the node's dirty blocks back to their PNGs.
+    ///
+    /// Blocks whose content hash matches the last saved image are not
+    /// re-encoded. Saving does not touch mtime: the data changed when it
+    /// was written, not when it reached the backing directory.
+    fn sync_node(&mut self, ino: Inode, mode: SyncMode) -> Result<(), c_int> {
+        let node = match self.nodes.get_mut(&ino) {
             Some(n) => n,
-            None => { reply.error(ENOENT); return; }
+            None => return Err(ENOENT),
+        };
+        let tail = if node.size % BLOCK_BYTES as u64 != 0 {
+            Some((node.size / BLOCK_BYTES as u64) as usize)
+        } else {
+            None
         };
+        let mut saved = Vec::new();
         for (&idx, buf) in node.dirty.iter() {
-            if idx >= node.blocks.len() { continue; }
-            let path = node.blocks[idx].clone();
-            if let Err(e) = ImageFS::save_block_to_path(&path, buf) {
-                eprintln!("fsync save error: {:?}", e);
-                reply.error(libc::EIO);
-                return;
+            let Some(path) = node.blocks.get(&idx) else { continue };
+            let fresh = node.dirty_at.get(&idx).is_some_and(|t| t.elapsed() < TAIL_FLUSH_DELAY);
+            if mode == SyncMode::DeferTail && Some(idx) == tail && fresh {
+                continue;
+            }
+            if let SyncMode::Range(first, last) = mode {
+                if idx < first || idx > last {
+                    continue;
+                }
             }
+            let hash = block_hash(buf);
+            if node.saved_hashes.get(&idx) == Some(&hash) {
+                self.encode_stats.encodes_skipped += 1;
+            } else {
+                // Blocks saved so far keep their hashes, so a retry skips them.
+                let bytes = match ImageFS::save_block(&*self.backend, path, buf) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        eprintln!("fsync save error: {:?}", e);
+                        return Err(retry::errno(&e));
+                    }
+                };
+                node.saved_hashes.insert(idx, hash);
+                node.missing.remove(&idx);
+                node.write_amp.encoded(bytes);
+                self.encode_stats.amp.encoded(bytes);
+            }
+            saved.push(idx);
         }
-        node.dirty.clear();
-        node.mtime = SystemTime::now();
-        reply.ok();
-    }
-
-    fn access(&mut self, _req: &Request<'_>, ino: Inode, _mask: i32, reply: ReplyEmpty) {
-        let st = self.state.lock().unwrap();
-        if st.nodes.contains_key(&ino) {
-            reply.ok();
-        } else {
-            reply.error(ENOENT);
+        for idx in saved {
+            node.dirty.remove(&idx);
+            node.dirty_at.remove(&idx);
         }
+        Ok(())
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
-        let mut st = self.state.lock().unwrap();
-        let parent_node = match st.nodes.get(&parent) {
-            Some(n) => n.clone(),
-            None => { reply.error(ENOENT); return; }
-        };
-        let full = FilesystemState::make_full(parent, &parent_node.name, &name.to_string_lossy());
-        let ino = match st.path_map.remove(&full) {
-            Some(i) => i,
-            None => { reply.error(ENOENT); return; }
-        };
-        if let Some(node) = st.nodes.remove(&ino) {
-            for p in node.blocks {
-                let _ = std::fs::remove_file(p);
+    /// Write back nodes holding blocks dirty for at least `TAIL_FLUSH_DELAY`.
+    ///
+    /// A transient failure is retried on a later pass rather than by
+    /// sleeping here, and only becomes the node's write-back error once
+    /// `--io-retries` passes in a row have failed.
+    fn flush_expired(&mut self) {
+        let due: Vec<Inode> = self
+            .nodes
+            .values()
+            .filter(|n| n.dirty_at.values().any(|t| t.elapsed() >= TAIL_FLUSH_DELAY))
+            .map(|n| n.ino)
+            .collect();
+        for ino in due {
+            let res = self.sync_node(ino, SyncMode::DeferTail);
+            let retries = self.opts.retry.retries;
+            let Some(node) = self.nodes.get_mut(&ino) else { continue };
+            match res {
+                Ok(()) => node.flush_retries = 0,
+                Err(retry::TRANSIENT) if node.flush_retries < retries => {
+                    node.flush_retries += 1;
+                    self.io_retries += 1;
+                }
+                Err(e) => {
+                    node.flush_retries = 0;
+                    node.writeback_error = Some(if e == retry::TRANSIENT { libc::EIO } else { e });
+                }
             }
         }
-        reply.ok();
     }
+}
 
-    fn flush(&mut self, _req: &Request<'_>, ino: Inode, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
-        self.fsync(_req, ino, 0, false, reply);
-    }
+/// The `--tunables` file, for re-reading it on SIGHUP.
+struct Tunables {
+    path: PathBuf,
+    /// Limits from the command line, which the file is applied over.
+    base: ThrottleLimits,
+    throttle: Arc<Mutex<Throttle>>,
+}
 
-    fn lseek(&mut self, _req: &Request<'_>, ino: Inode, _fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
-        let st = self.state.lock().unwrap();
-        let node = match st.nodes.get(&ino) {
-            Some(n) => n.clone(),
-            None => { reply.error(ENOENT); return; }
-        };
-        let newoff = match whence {
-            libc::SEEK_SET => offset,
-            libc::SEEK_CUR => offset,
-            libc::SEEK_END => node.size as i64 + offset,
-            _ => { reply.error(EINVAL); return; }
+impl Tunables {
+    /// Re-read the file and switch to its limits, logging each change.
+    /// A file that does not parse changes nothing.
+    fn reload(&self) {
+        let limits = match self.base.load(&self.path) {
+            Ok(limits) => limits,
+            Err(e) => {
+                eprintln!("imgfs: reload: {}; limits unchanged", e);
+                return;
+            }
         };
-        if newoff < 0 { reply.error(EINVAL); return; }
-        reply.offset(newoff);
+        let mut throttle = self.throttle.lock().unwrap();
+        let changes = throttle.limits().changes(&limits);
+        if changes.is_empty() {
+            eprintln!("imgfs: reload: {}: no changes", self.path.display());
+        }
+        for change in changes {
+            eprintln!("imgfs: reload: {}", change);
+        }
+        throttle.set_limits(limits);
     }
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage: {} <mountpoint> <backing_dir>", args[0]);
-        std::process::exit(1);
+struct ImageFS {
+    state: Arc<Mutex<FilesystemState>>,
+    tracer: Option<Tracer>,
+    /// Shared with the signal thread, which changes the limits on SIGHUP.
+    throttle: Arc<Mutex<Throttle>>,
+    /// Copy of `MountOptions::retry`, usable without the state lock.
+    retry: RetryPolicy,
+    /// Calls to operations ImageFS does not implement, by operation name.
+    unsupported: BTreeMap<&'static str, u64>,
+}
+
+impl ImageFS {
+    fn new(
+        backing: PathBuf,
+        backend: Box<dyn BlockBackend>,
+        opts: MountOptions,
+        tracer: Option<Tracer>,
+    ) -> io::Result<Self> {
+        let throttle = Arc::new(Mutex::new(Throttle::new(opts.throttle)));
+        let retry = opts.retry;
+        let state = FilesystemState::new(backing, backend, opts)?;
+        Ok(Self { state: Arc::new(Mutex::new(state)), tracer, throttle, retry, unsupported: BTreeMap::new() })
     }
-    let mountpoint = &args[1];
-    let backing = PathBuf::from(&args[2]);
-    std::fs::create_dir_all(&backing).expect("create backing dir");
 
-    let fs = ImageFS::new(backing);
+    /// Count a call to an operation ImageFS does not implement and return
+    /// `errno` for it. Only the first call of each operation is logged;
+    /// the totals are reported at unmount, to show which missing features
+    /// real workloads ask for.
+    fn unsupported(&mut self, op: &'static str, errno: c_int) -> c_int {
+        let calls = self.unsupported.entry(op).or_insert(0);
+        if *calls == 0 {
+            eprintln!("imgfs: {} is not supported; further calls are only counted", op);
+        }
+        *calls += 1;
+        errno
+    }
+
+    /// Lock the state and run `op` on it, retrying transient block IO
+    /// failures with the lock released during each backoff.
+    fn with_retry<T>(
+        &self,
+        op: impl FnMut(&mut FilesystemState) -> Result<T, c_int>,
+    ) -> (MutexGuard<'_, FilesystemState>, Result<T, c_int>) {
+        self.retry.run(&self.state, |st| st.io_retries += 1, op)
+    }
+
+    /// Record the outcome of a mutating operation when tracing is enabled.
+    ///
+    /// The record is only built when a tracer is installed, so a disabled
+    /// trace costs a single branch.
+    fn trace<T>(&self, rec: impl FnOnce() -> TraceRecord, res: &Result<T, c_int>) {
+        if let Some(tracer) = &self.tracer {
+            let mut rec = rec();
+            rec.result = res.as_ref().err().copied().unwrap_or(0);
+            tracer.record(rec);
+        }
+    }
+
+    /// Periodically encode blocks that have been dirty for
+    /// `TAIL_FLUSH_DELAY`.
+    fn spawn_tail_flusher(&self) {
+        let state = Arc::clone(&self.state);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+            state.lock().unwrap().flush_expired();
+        });
+    }
+
+    /// Refresh the heartbeat and health of the runtime record until it is
+    /// taken out of `record` at unmount.
+    fn spawn_heartbeat(state: Arc<Mutex<FilesystemState>>, record: Arc<Mutex<Option<MountRecord>>>) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(runtime::HEARTBEAT_INTERVAL);
+            let degraded = state.lock().unwrap().nodes.values().any(|n| n.writeback_error.is_some());
+            let mut record = record.lock().unwrap();
+            let Some(r) = record.as_mut() else { return };
+            r.heartbeat = SystemTime::now();
+            r.health = if degraded { "degraded" } else { "ok" }.to_string();
+            if let Err(e) = r.save() {
+                eprintln!("imgfs: cannot update {}: {}", r.path().display(), e);
+            }
+        });
+    }
+
+    /// Handle SIGINT/SIGTERM: report what is still pending, write it back
+    /// and unmount once that succeeds within `timeout`.
+    ///
+    /// If the write-back fails or times out the filesystem stays mounted,
+    /// so nothing is silently lost; a second signal, or `force`, unmounts
+    /// anyway. SIGHUP re-reads `tunables`, if there is one.
+    fn spawn_signal_handler(
+        state: Arc<Mutex<FilesystemState>>,
+        mut unmounter: SessionUnmounter,
+        mountpoint: PathBuf,
+        timeout: Duration,
+        force: bool,
+        tunables: Option<Tunables>,
+    ) {
+        std::thread::spawn(move || {
+            let mut forced = force;
+            loop {
+                let sig = match signals::wait_signal() {
+                    Signal::Terminate(name) => name,
+                    Signal::Reload => {
+                        match &tunables {
+                            Some(t) => t.reload(),
+                            None => eprintln!("imgfs: SIGHUP received, but there is no --tunables file to reload"),
+                        }
+                        continue;
+                    }
+                };
+                let (handles, dirty) = state.lock().unwrap().pending_writes();
+                eprintln!(
+                    "imgfs: {} received: {} open write handles, {} dirty bytes; flushing",
+                    sig, handles, dirty
+                );
+
+                let (tx, rx) = std::sync::mpsc::channel();
+                let flusher = Arc::clone(&state);
+                std::thread::spawn(move || {
+                    let retry = flusher.lock().unwrap().opts.retry;
+                    let (_st, res) = retry.run(&flusher, |st| st.io_retries += 1, FilesystemState::sync_all);
+                    let _ = tx.send(res);
+                });
+                let done = match rx.recv_timeout(timeout) {
+                    Ok(Ok(())) => true,
+                    Ok(Err(_)) => {
+                        eprintln!("imgfs: write-back failed");
+                        false
+                    }
+                    Err(_) => {
+                        eprintln!("imgfs: write-back did not finish within {}s", timeout.as_secs());
+                        false
+                    }
+                };
+                if done || forced {
+                    if !done {
+                        eprintln!("imgfs: forcing unmount, unsaved data is lost");
+                    }
+                    if let Err(e) = unmounter.unmount() {
+                        eprintln!("imgfs: unmount failed: {}", e);
+                    } else if still_mounted(&mountpoint) {
+                        eprintln!("imgfs: {} is busy, unmount it once it is closed", mountpoint.display());
+                    }
+                    return;
+                }
+                eprintln!("imgfs: staying mounted; signal again to unmount anyway");
+                forced = true;
+            }
+        });
+    }
+
+    /// Expire trash in the background. The reaper removes entries without
+    /// a request from the kernel, so it tells the kernel to drop them from
+    /// its dentry cache through `notifier`.
+    fn spawn_trash_reaper(state: Arc<Mutex<FilesystemState>>, notifier: Notifier) {
+        std::thread::spawn(move || loop {
+            std::thread::sleep(TRASH_SWEEP_INTERVAL);
+            let (trash, names) = state.lock().unwrap().expire_trash();
+            // Not holding the lock: the kernel may call back into us. An
+            // entry the kernel never looked up is not cached, so ENOENT
+            // is expected and ignored.
+            for name in names {
+                let _ = notifier.inval_entry(trash, OsStr::new(&name));
+            }
+        });
+    }
+
+    fn load_block(backend: &dyn BlockBackend, path: &Path) -> io::Result<Vec<u8>> {
+        if !backend.exists(path) {
+            return Ok(vec![0u8; BLOCK_BYTES]);
+        }
+        let bytes = backend.load(path)?;
+        let img = image::load_from_memory(&bytes).map_err(io::Error::other)?;
+        let gray = img.to_luma8();
+        let mut out = vec![0u8; BLOCK_BYTES];
+        let w = gray.width() as usize;
+        let h = gray.height() as usize;
+        for y in 0..BLOCK_H {
+            for x in 0..BLOCK_W {
+                let idx = y * BLOCK_W + x;
+                if x < w && y < h {
+                    out[idx] = gray.get_pixel(x as u32, y as u32)[0];
+                } else {
+                    out[idx] = 0;
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Encode `buf` as the PNG at `path` and return its encoded size.
+    fn save_block(backend: &dyn BlockBackend, path: &Path, buf: &[u8]) -> io::Result<u64> {
+        assert_eq!(buf.len(), BLOCK_BYTES);
+        let mut imgbuf: GrayImage = ImageBuffer::new(BLOCK_W as u32, BLOCK_H as u32);
+        for y in 0..BLOCK_H {
+            for x in 0..BLOCK_W {
+                let value = buf[y * BLOCK_W + x];
+                imgbuf.put_pixel(x as u32, y as u32, Luma([value]));
+            }
+        }
+        let mut png = Vec::new();
+        imgbuf
+            .write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .map_err(io::Error::other)?;
+        // The backend keeps the OS error so transient failures can be retried.
+        backend.save(path, &png)?;
+        Ok(png.len() as u64)
+    }
+}
+
+impl Filesystem for ImageFS {
+    fn destroy(&mut self) {
+        let inos: Vec<Inode> = self.state.lock().unwrap().nodes.keys().copied().collect();
+        for ino in inos {
+            let _ = self.with_retry(|st| st.sync_node(ino, SyncMode::All));
+        }
+        let mut st = self.state.lock().unwrap();
+        if let Err(e) = st.save_manifest() {
+            eprintln!("imgfs: cannot save manifest: {}", e);
+        }
+        eprintln!(
+            "imgfs: {} blocks encoded, {} unchanged blocks skipped",
+            st.encode_stats.amp.blocks_encoded, st.encode_stats.encodes_skipped
+        );
+        let amp = st.encode_stats.amp;
+        if amp.logical_bytes > 0 {
+            eprintln!(
+                "imgfs: {} bytes written, {} bytes encoded (write amplification {:.2}x)",
+                amp.logical_bytes, amp.encoded_bytes, amp.ratio()
+            );
+            let mut worst: Vec<&FileNode> = st.nodes.values().filter(|n| n.write_amp.encoded_bytes > 0).collect();
+            // Rank by the bytes encoded beyond those written.
+            worst.sort_by_key(|n| {
+                std::cmp::Reverse(n.write_amp.encoded_bytes.saturating_sub(n.write_amp.logical_bytes))
+            });
+            for node in worst.iter().take(WRITE_AMP_REPORTED_FILES) {
+                let a = &node.write_amp;
+                eprintln!(
+                    "imgfs:   {}: {} bytes written, {} bytes encoded ({:.2}x)",
+                    node.name, a.logical_bytes, a.encoded_bytes, a.ratio()
+                );
+            }
+        }
+        if st.opts.io_stats {
+            let mut read: Vec<&FileNode> = st.nodes.values().filter(|n| n.io_stats.reads > 0).collect();
+            read.sort_by_key(|n| std::cmp::Reverse(n.io_stats.bytes_read));
+            if !read.is_empty() {
+                eprintln!("imgfs: most read files:");
+            }
+            for node in read.iter().take(IO_STATS_REPORTED_FILES) {
+                let s = &node.io_stats;
+                let by = s.last_uid.map(|u| format!(", last by uid {}", u)).unwrap_or_default();
+                eprintln!("imgfs:   {}: {} reads, {} bytes{}", node.name, s.reads, s.bytes_read, by);
+            }
+        }
+        if st.io_retries > 0 {
+            eprintln!("imgfs: {} operations retried after transient block IO errors", st.io_retries);
+        }
+        if st.dirty_limit_hits > 0 {
+            eprintln!(
+                "imgfs: dirty limit reached {} times, {} blocks written back early",
+                st.dirty_limit_hits, st.forced_flushes
+            );
+        }
+        let throttle = self.throttle.lock().unwrap();
+        if throttle.enabled() {
+            let t = &throttle.stats;
+            eprintln!(
+                "imgfs: {} ops, {} bytes read, {} bytes written; {} ops throttled for {:.1}s",
+                t.ops, t.bytes_read, t.bytes_written, t.throttled_ops, t.throttled_for.as_secs_f64()
+            );
+        }
+        if !self.unsupported.is_empty() {
+            let calls: Vec<String> = self.unsupported.iter().map(|(op, n)| format!("{} {}", op, n)).collect();
+            eprintln!("imgfs: unsupported calls: {}", calls.join(", "));
+        }
+        if let Some(tracer) = &self.tracer {
+            let dropped = tracer.dropped();
+            if dropped > 0 {
+                eprintln!("trace: {} records dropped because the writer fell behind or stopped", dropped);
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: Inode, _fh: Option<u64>, reply: ReplyAttr) {
+        let st = self.state.lock().unwrap();
+        match st.nodes.get(&ino) {
+            Some(node) => reply.attr(&TTL, &node.attr(st.opts.io_size)),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &fuser::Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: fuser::ReplyAttr,
+    ) {
+        let chown = uid.is_some() || gid.is_some();
+        // Permissions are checked up front, so a setattr that is refused
+        // in part changes nothing.
+        let allowed = {
+            let st = self.state.lock().unwrap();
+            let caller = (req.uid(), req.gid());
+            size.map_or(Ok(()), |_| st.check_truncate(ino, fh, caller))
+                .and_then(|()| if chown { st.check_chown(ino, uid, gid, caller) } else { Ok(()) })
+                .and_then(|()| mode.map_or(Ok(()), |_| st.check_chmod(ino, caller)))
+        };
+        if let Err(e) = allowed {
+            self.trace(|| TraceRecord::new("setattr", ino), &Err::<(), _>(e));
+            reply.error(e);
+            return;
+        }
+        let (mut st, res) = self.with_retry(|st| size.map_or(Ok(()), |size| st.truncate(ino, size)));
+        if let Some(size) = size {
+            self.trace(|| TraceRecord::new("truncate", ino).range(size as i64, 0), &res);
+            if let Err(e) = res {
+                reply.error(e);
+                return;
+            }
+        }
+        let io_size = st.opts.io_size;
+
+        if chown {
+            let res = st.chown(ino, uid, gid, (req.uid(), req.gid()));
+            self.trace(|| TraceRecord::new("chown", ino), &res);
+            if let Err(e) = res {
+                reply.error(e);
+                return;
+            }
+        }
+
+        if let Some(mode) = mode {
+            let res = st.chmod(ino, mode, (req.uid(), req.gid()));
+            self.trace(|| TraceRecord::new("chmod", ino), &res);
+            if let Err(e) = res {
+                reply.error(e);
+                return;
+            }
+        }
+
+        let now = std::time::SystemTime::now();
+        if atime.is_some() || mtime.is_some() || crtime.is_some() {
+            let res = st.set_times(ino, atime, mtime, crtime, (req.uid(), req.gid()), now);
+            self.trace(|| TraceRecord::new("utimens", ino), &res);
+            if let Err(e) = res {
+                reply.error(e);
+                return;
+            }
+        }
+
+        let node = match st.nodes.get_mut(&ino) {
+            Some(n) => n,
+            None => {
+                self.trace(|| TraceRecord::new("setattr", ino), &Err::<(), _>(ENOENT));
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+
+        if atime.is_some() || mtime.is_some() || crtime.is_some() || mode.is_some() || size.is_some() || chown {
+            node.ctime = now;
+        }
+
+        let attr = node.attr(io_size);
+        self.trace(|| TraceRecord::new("setattr", ino), &Ok::<_, c_int>(()));
+        reply.attr(&std::time::Duration::from_secs(1), &attr);
+    }
+
+    fn lookup(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let st = self.state.lock().unwrap();
+
+        let parent_name = match st.dir_path(parent) {
+            Ok(n) => n,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        let full = match st.entry_name(name) {
+            Ok(n) => FilesystemState::make_full(parent, &parent_name, &n),
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        let ino = match st.lookup_path(&full) {
+            Some(i) => i,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        let node = match st.nodes.get(&ino) {
+            Some(n) => n,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+
+        reply.entry(&TTL, &node.attr(st.opts.io_size), 0);
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let mut st = self.state.lock().unwrap();
+        let res = st.create_node(parent, name, false, create_perm(mode, umask), (req.uid(), req.gid()));
+        self.trace(|| TraceRecord::new("create", *res.as_ref().unwrap_or(&0)).entry(parent, name), &res);
+        let ino = match res {
+            Ok(ino) => ino,
+            Err(e) => { reply.error(e); return; }
+        };
+        let fh = st.open_handle(ino, flags);
+        let attr = st.nodes[&ino].attr(st.opts.io_size);
+        reply.created(&TTL, &attr, 0, fh, open_reply_flags(flags));
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: Inode, flags: i32, reply: ReplyOpen) {
+        let mut st = self.state.lock().unwrap();
+        match st.nodes.get(&ino) {
+            None => { reply.error(ENOENT); return; }
+            Some(n) if n.is_dir && flags & libc::O_ACCMODE != libc::O_RDONLY => {
+                reply.error(EISDIR);
+                return;
+            }
+            Some(n) => {
+                if let Err(e) = n.check_access((req.uid(), req.gid()), open_mask(flags)) {
+                    reply.error(e);
+                    return;
+                }
+            }
+        }
+        let fh = st.open_handle(ino, flags);
+        reply.opened(fh, open_reply_flags(flags));
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: Inode,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.state.lock().unwrap().handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn read(
+        &mut self,
+        req: &Request<'_>,
+        ino: Inode,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let wait = self.throttle.lock().unwrap().read(size as u64);
+        std::thread::sleep(wait);
+        let (mut st, res) = self.with_retry(|st| {
+            let ino = st.resolve_handle(fh, ino)?;
+            // Direct reads already bypass any cache: clean blocks are always
+            // decoded from their PNG and dirty ones are the current contents.
+            if st.is_direct(fh) && !direct_aligned(offset, size as u64) {
+                return Err(EINVAL);
+            }
+            st.read_at(ino, offset as u64, size).map(|out| (ino, out))
+        });
+        match res {
+            Ok((ino, out)) => {
+                reply.data(&out);
+                if st.opts.io_stats && !out.is_empty() {
+                    st.record_io(ino, req.uid(), false, out.len());
+                }
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: Inode,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let wait = self.throttle.lock().unwrap().write(data.len() as u64);
+        std::thread::sleep(wait);
+        let (mut st, res) = self.with_retry(|st| {
+            let ino = st.resolve_handle(fh, ino)?;
+            let written =
+                if st.is_direct(fh) { st.write_direct(ino, offset, data) } else { st.write_at(ino, offset, data) }?;
+            Ok((ino, written))
+        });
+        self.trace(|| TraceRecord::new("write", ino).range(offset, data.len() as u64), &res);
+        match res {
+            Ok((ino, written)) => {
+                reply.written(written as u32);
+                if st.opts.io_stats {
+                    st.record_io(ino, req.uid(), true, written);
+                }
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: Inode,
+        name: &OsStr,
+        newparent: Inode,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let (_st, res) = self.with_retry(|st| st.rename(parent, name, newparent, newname, (req.uid(), req.gid())));
+        self.trace(|| TraceRecord::new("rename", 0).entry(parent, name), &res);
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: Inode,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let mut st = self.state.lock().unwrap();
+        let res = st.create_node(parent, name, true, create_perm(mode, umask), (req.uid(), req.gid()));
+        self.trace(|| TraceRecord::new("mkdir", *res.as_ref().unwrap_or(&0)).entry(parent, name), &res);
+        match res {
+            Ok(ino) => reply.entry(&TTL, &st.nodes[&ino].attr(st.opts.io_size), 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, req: &Request<'_>, _ino: Inode, reply: ReplyStatfs) {
+        let st = self.state.lock().unwrap();
+        let mut blocks = 1_000_000u64;
+        // Trashed files keep their blocks until they expire, so they count
+        // as used here even though they are reclaimable.
+        let mut used: u64 = st.nodes.values().map(|n| n.blocks.len() as u64).sum();
+        // Callers with a quota see their own limit instead of the whole fs.
+        if let Some(Quota { used_blocks, limit: Some(limit) }) = st.quotas.get(&req.uid()) {
+            blocks = *limit;
+            used = *used_blocks;
+        }
+        let free = blocks.saturating_sub(used);
+        // Counts are in whole blocks (f_frsize); f_bsize matches st_blksize.
+        reply.statfs(
+            blocks,
+            free,
+            free,
+            st.nodes.len() as u64,
+            0,
+            st.opts.io_size,
+            NAME_MAX as u32,
+            BLOCK_BYTES as u32,
+        );
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: Inode, _flags: i32, reply: ReplyOpen) {
+        let mut st = self.state.lock().unwrap();
+        match st.open_dir_handle(ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, _ino: Inode, fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let st = self.state.lock().unwrap();
+        let entries = match st.read_dir_handle(fh, offset) {
+            Ok(entries) => entries,
+            Err(e) => { reply.error(e); return; }
+        };
+        for (next, e) in entries {
+            if reply.add(e.ino, next, e.kind, &e.name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(&mut self, _req: &Request<'_>, _ino: Inode, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.state.lock().unwrap().dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: Inode, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let ino = match self.state.lock().unwrap().resolve_handle(fh, ino) {
+            Ok(ino) => ino,
+            Err(e) => { reply.error(e); return; }
+        };
+        let (mut st, res) = self.with_retry(|st| st.sync_node(ino, SyncMode::All).and_then(|()| st.commit_manifest()));
+        if res.is_ok() {
+            if let Some(node) = st.nodes.get_mut(&ino) {
+                node.writeback_error = None;
+            }
+        }
+        self.trace(|| TraceRecord::new("fsync", ino), &res);
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: Inode, mask: i32, reply: ReplyEmpty) {
+        let st = self.state.lock().unwrap();
+        match st.check_access(ino, (req.uid(), req.gid()), mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, ino: Inode, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let st = self.state.lock().unwrap();
+        let node = match st.nodes.get(&ino) {
+            Some(n) => n,
+            None => { reply.error(ENOENT); return; }
+        };
+        match node.virtual_xattr(&name.to_string_lossy()) {
+            Some(value) => reply_xattr(reply, size, &value),
+            None => reply.error(libc::ENODATA),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: Inode, size: u32, reply: ReplyXattr) {
+        let st = self.state.lock().unwrap();
+        if !st.nodes.contains_key(&ino) {
+            reply.error(ENOENT);
+            return;
+        }
+        // NUL-terminated names, back to back.
+        let mut names = Vec::new();
+        for name in VIRTUAL_XATTRS {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        reply_xattr(reply, size, &names);
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: Inode,
+        name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        if name == EMPTY_TRASH_XATTR {
+            // The kernel is not told about the freed entries: notifying
+            // from inside a request could deadlock, and they expire with
+            // the entry TTL anyway.
+            let res = self.state.lock().unwrap().empty_trash(ino, (req.uid(), req.gid()));
+            self.trace(|| TraceRecord::new("empty_trash", ino), &res);
+            match res {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        // The virtual attributes are computed; nothing else is stored.
+        if VIRTUAL_XATTRS.contains(&&*name.to_string_lossy()) {
+            reply.error(EPERM);
+        } else {
+            reply.error(libc::ENOTSUP);
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, _ino: Inode, name: &OsStr, reply: ReplyEmpty) {
+        if VIRTUAL_XATTRS.contains(&&*name.to_string_lossy()) {
+            reply.error(EPERM);
+        } else {
+            reply.error(libc::ENODATA);
+        }
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
+        let mut st = self.state.lock().unwrap();
+        let res = st.unlink(parent, name, (req.uid(), req.gid()));
+        self.trace(|| TraceRecord::new("unlink", 0).entry(parent, name), &res);
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: Inode, name: &OsStr, reply: ReplyEmpty) {
+        let mut st = self.state.lock().unwrap();
+        let res = st.rmdir(parent, name, (req.uid(), req.gid()));
+        self.trace(|| TraceRecord::new("rmdir", 0).entry(parent, name), &res);
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    /// Called on every close(). Data is left to fsync and the background
+    /// flusher, as close() promises no durability; only an error of an
+    /// earlier background write-back is reported, so it is not lost.
+    /// `--flush-on-close` restores writing the file back here.
+    fn flush(&mut self, _req: &Request<'_>, ino: Inode, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let ino = match self.state.lock().unwrap().resolve_handle(fh, ino) {
+            Ok(ino) => ino,
+            Err(e) => { reply.error(e); return; }
+        };
+        let (_st, res) = self.with_retry(|st| {
+            if st.opts.flush_on_close {
+                st.sync_node(ino, SyncMode::All)
+            } else {
+                match st.nodes.get_mut(&ino) {
+                    Some(node) => node.writeback_error.take().map_or(Ok(()), Err),
+                    None => Err(ENOENT),
+                }
+            }
+        });
+        self.trace(|| TraceRecord::new("flush", ino), &res);
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn lseek(&mut self, _req: &Request<'_>, ino: Inode, fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        let st = self.state.lock().unwrap();
+        let ino = match st.resolve_handle(fh, ino) {
+            Ok(ino) => ino,
+            Err(e) => { reply.error(e); return; }
+        };
+        let node = match st.nodes.get(&ino) {
+            Some(n) => n,
+            None => { reply.error(ENOENT); return; }
+        };
+        // The kernel resolves SEEK_SET, SEEK_CUR and SEEK_END itself and
+        // only forwards SEEK_DATA/SEEK_HOLE. ImageFS does not track the
+        // file position, so SEEK_CUR cannot be answered here. A negative
+        // or overflowing result is EINVAL, as lseek(2) requires.
+        let start = u64::try_from(offset).map_err(|_| EINVAL);
+        let res = match whence {
+            libc::SEEK_SET => start,
+            libc::SEEK_END => (node.size as i64)
+                .checked_add(offset)
+                .and_then(|end| u64::try_from(end).ok())
+                .ok_or(EINVAL),
+            libc::SEEK_DATA => start.and_then(|off| node.seek_data_hole(off, true, &*st.backend)),
+            libc::SEEK_HOLE => start.and_then(|off| node.seek_data_hole(off, false, &*st.backend)),
+            _ => Err(EINVAL),
+        };
+        match res {
+            Ok(off) => reply.offset(off as i64),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn ioctl(
+        &mut self,
+        req: &Request<'_>,
+        ino: Inode,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        _out_size: u32,
+        reply: ReplyIoctl,
+    ) {
+        if cmd != FITRIM {
+            reply.error(self.unsupported("ioctl", libc::ENOTTY));
+            return;
+        }
+        // struct fstrim_range { u64 start, len, minlen }
+        if in_data.len() < 24 {
+            reply.error(EINVAL);
+            return;
+        }
+        // Like the kernel, trimming needs CAP_SYS_ADMIN; root stands in for it.
+        if req.uid() != 0 {
+            reply.error(EPERM);
+            return;
+        }
+        let mut range = [0u8; 24];
+        range.copy_from_slice(&in_data[..24]);
+        let min_len = u64::from_ne_bytes(range[16..24].try_into().unwrap());
+
+        let mut st = self.state.lock().unwrap();
+        let res = st.trim_orphans(min_len).map_err(|e| {
+            eprintln!("imgfs: trim failed: {}", e);
+            libc::EIO
+        });
+        self.trace(|| TraceRecord::new("trim", ino), &res);
+        match res {
+            Ok(trimmed) => {
+                // On return, len holds the number of bytes trimmed.
+                range[8..16].copy_from_slice(&trimmed.to_ne_bytes());
+                reply.ioctl(0, &range);
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    // Operations ImageFS does not implement. Each answers with the errno
+    // a local filesystem without the feature would give, so callers fall
+    // back instead of failing, and is counted.
+
+    /// Only regular files and directories exist; mknod is only sent for
+    /// the other types.
+    fn mknod(
+        &mut self,
+        _req: &Request<'_>,
+        _parent: Inode,
+        _name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        reply.error(self.unsupported("mknod", EPERM));
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request<'_>,
+        _parent: Inode,
+        _link_name: &OsStr,
+        _target: &Path,
+        reply: ReplyEntry,
+    ) {
+        reply.error(self.unsupported("symlink", EPERM));
+    }
+
+    fn link(&mut self, _req: &Request<'_>, _ino: Inode, _newparent: Inode, _newname: &OsStr, reply: ReplyEntry) {
+        reply.error(self.unsupported("link", EPERM));
+    }
+
+    /// ENOSYS tells the kernel to stop asking and report the file as
+    /// always ready, which it is.
+    fn poll(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: Inode,
+        _fh: u64,
+        _ph: PollHandle,
+        _events: u32,
+        _flags: u32,
+        reply: ReplyPoll,
+    ) {
+        reply.error(self.unsupported("poll", libc::ENOSYS));
+    }
+
+    /// posix_fallocate falls back to writing the range on EOPNOTSUPP.
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: Inode,
+        _fh: u64,
+        _offset: i64,
+        _length: i64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(self.unsupported("fallocate", libc::EOPNOTSUPP));
+    }
+
+    /// ENOSYS makes the kernel fall back to copying through read and write.
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        _ino_in: Inode,
+        _fh_in: u64,
+        _offset_in: i64,
+        _ino_out: Inode,
+        _fh_out: u64,
+        _offset_out: i64,
+        _len: u64,
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        reply.error(self.unsupported("copy_file_range", libc::ENOSYS));
+    }
+}
+
+/// Command-line interface for the image-backed filesystem.
+///
+/// Usage:
+///
+/// ```bash
+/// bwfs [OPTIONS] <mountpoint> <backing>
+/// ```
+#[derive(Parser)]
+struct Cli {
+    /// Directory to mount the filesystem on.
+    mountpoint: String,
+
+    /// Directory holding the block images (optionally as `dir:PATH`), or
+    /// `tar:ARCHIVE` to mount a tar of one read-only.
+    backing: String,
+
+    /// Name of this instance, shown as the source `imgfs:NAME` in
+    /// /proc/mounts and df, and in its runtime record.
+    #[arg(long, value_parser = parse_name)]
+    name: Option<String>,
+
+    /// Move unlinked files into /.bwfs_trash instead of deleting them.
+    /// Setting the user.bwfs.empty_trash xattr on it empties it.
+    #[arg(long)]
+    trash: bool,
+
+    /// Seconds a trashed file is kept before its blocks are freed.
+    #[arg(long, default_value_t = 7 * 24 * 60 * 60)]
+    trash_retention: u64,
+
+    /// Limit the blocks owned by a uid (repeatable).
+    #[arg(long = "quota", value_name = "UID=BLOCKS", value_parser = parse_quota)]
+    quotas: Vec<(u32, u64)>,
+
+    /// Append a JSON line per mutating operation to this file.
+    #[arg(long, value_name = "PATH")]
+    trace_file: Option<PathBuf>,
+
+    /// Cap reads through the mount at this many bytes per second.
+    #[arg(long, value_name = "BYTES")]
+    max_read_bps: Option<u64>,
+
+    /// Cap writes through the mount at this many bytes per second.
+    #[arg(long, value_name = "BYTES")]
+    max_write_bps: Option<u64>,
+
+    /// Cap reads plus writes at this many operations per second.
+    #[arg(long, value_name = "OPS")]
+    max_iops: Option<u64>,
+
+    /// File of `key = value` throttle limits applied over --max-read-bps,
+    /// --max-write-bps and --max-iops, and re-read on SIGHUP.
+    #[arg(long, value_name = "PATH")]
+    tunables: Option<PathBuf>,
+
+    /// Retry a block load or save this many times on transient errors.
+    #[arg(long, default_value_t = 3)]
+    io_retries: u32,
+
+    /// Milliseconds before the first retry, doubled for each one after it.
+    #[arg(long, default_value_t = 10)]
+    io_retry_delay_ms: u64,
+
+    /// Match names case-insensitively while preserving their case.
+    #[arg(long)]
+    casefold: bool,
+
+    /// Normalize UTF-8 names to this Unicode form before storing them.
+    #[arg(long, value_enum, value_name = "FORM")]
+    normalize: Option<NameForm>,
+
+    /// Override the st_blksize reported to applications, in bytes.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(512..))]
+    blksize: Option<u32>,
+
+    /// Write all dirty data before each manifest update (ordered), or
+    /// only the file being synced (writeback).
+    #[arg(long, value_enum, default_value_t = DataMode::Ordered)]
+    data: DataMode,
+
+    /// Refuse to grow a file past this many bytes (EFBIG). A uid with a
+    /// quota is also limited to the size of its quota.
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_FILE_SIZE)]
+    max_file_size: u64,
+
+    /// Bytes of dirty data kept in memory across all files; writes past
+    /// this first write back the oldest dirty blocks. Rounded down to
+    /// whole blocks, at least one.
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_DIRTY)]
+    max_dirty: u64,
+
+    /// Seconds to wait for dirty data to be written back after SIGINT or
+    /// SIGTERM before giving up.
+    #[arg(long, default_value_t = 30)]
+    unmount_timeout: u64,
+
+    /// Unmount even if dirty data could not be written back in time.
+    #[arg(long)]
+    force_unmount: bool,
+
+    /// Write a file's dirty data back on every close, as older versions
+    /// did, instead of leaving it to fsync and the background flusher.
+    #[arg(long)]
+    flush_on_close: bool,
+
+    /// Mount even if the mountpoint is not empty, hiding its contents.
+    #[arg(long)]
+    allow_nonempty: bool,
+
+    /// Check the mountpoint, backing store, manifest and /dev/fuse, print
+    /// the result of each and exit without mounting.
+    #[arg(long)]
+    check_only: bool,
+
+    /// Print the `--check-only` report as JSON.
+    #[arg(long, requires = "check_only")]
+    json: bool,
+
+    /// Count reads and writes per file, shown in the user.bwfs.stats
+    /// xattr and as a most-read summary at unmount.
+    #[arg(long)]
+    io_stats: bool,
+
+    /// Like --io-stats, and also record the uid of each file's last
+    /// reader or writer.
+    #[arg(long)]
+    audit: bool,
+
+    /// Do not write back a file's data and the manifest when a rename
+    /// makes it replace another file. Faster, but a crash after such a
+    /// rename can lose both the old and the new content.
+    #[arg(long)]
+    no_rename_barrier: bool,
+
+    /// Before mounting, move block images no file references into
+    /// lost+found in the backing directory, or delete them.
+    #[arg(
+        long, value_enum, value_name = "MODE",
+        num_args = 0..=1, require_equals = true, default_missing_value = "move"
+    )]
+    gc: Option<GcMode>,
+}
+
+/// Accept a `--name` made of characters that survive mount option
+/// strings: ASCII letters, digits, `_`, `-` and `.`.
+fn parse_name(s: &str) -> Result<String, String> {
+    let ok = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    if s.is_empty() || !s.chars().all(ok) {
+        return Err("expected ASCII letters, digits, '_', '-' or '.'".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// FUSE mount options for an instance called `name`, whose source
+/// `imgfs:NAME` tells it apart from other mounts in /proc/mounts and df.
+fn mount_options(name: Option<&str>, read_only: bool) -> Vec<MountOption> {
+    vec![
+        MountOption::FSName(name.map_or("imgfs".to_string(), |name| format!("imgfs:{}", name))),
+        MountOption::AutoUnmount,
+        if read_only { MountOption::RO } else { MountOption::RW },
+    ]
+}
+
+/// Parse a `--quota` value of the form `UID=BLOCKS`.
+fn parse_quota(s: &str) -> Result<(u32, u64), String> {
+    let (uid, blocks) = s.split_once('=').ok_or("expected UID=BLOCKS")?;
+    let uid = uid.trim().parse().map_err(|_| format!("invalid uid `{}`", uid))?;
+    let blocks = blocks.trim().parse().map_err(|_| format!("invalid block count `{}`", blocks))?;
+    Ok((uid, blocks))
+}
+
+/// Open the backing store named by `args`, creating a missing
+/// directory if `create`. Errors carry the exit status to report them with.
+fn open_backing(args: &Cli, create: bool) -> Result<(PathBuf, Box<dyn BlockBackend>), (i32, String)> {
+    let (root, backend) = backend::open(&args.backing, create).map_err(|e| {
+        let code = if e.kind() == io::ErrorKind::NotFound { EXIT_NOT_FOUND } else { EXIT_FAILURE };
+        (code, format!("{}: {}", args.backing, e))
+    })?;
+    if backend.read_only() && args.gc.is_some() {
+        return Err((EXIT_CONFIG, "--gc needs a writable backing store".to_string()));
+    }
+    Ok((root, backend))
+}
+
+/// One line of the `--check-only` report.
+#[derive(serde::Serialize)]
+struct CheckResult {
+    check: &'static str,
+    ok: bool,
+    message: String,
+}
+
+/// `--check-only`: run every check a mount depends on without mounting
+/// or creating anything, print one line per check and return the exit
+/// status of the first failure, or 0.
+fn check_only(args: &Cli) -> i32 {
+    let mut results = Vec::new();
+    let mut status = 0;
+    let mut record = |check, res: Result<String, (i32, String)>| {
+        let (ok, message) = match res {
+            Ok(msg) => (true, msg),
+            Err((code, msg)) => {
+                if status == 0 {
+                    status = code;
+                }
+                (false, msg)
+            }
+        };
+        results.push(CheckResult { check, ok, message });
+    };
+
+    record("mountpoint", validate_mountpoint(&args.mountpoint, args.allow_nonempty).map(|()| args.mountpoint.clone()));
+    match open_backing(args, false) {
+        Ok((root, backend)) => {
+            let kind = if backend.read_only() {
+                "read-only"
+            } else if !root.exists() {
+                "missing, will be created"
+            } else {
+                "read-write"
+            };
+            record("backing", Ok(format!("{} ({})", root.display(), kind)));
+            let manifest = Manifest::load(&*backend, &root).map_err(|e| (EXIT_FAILURE, format!("manifest: {}", e)));
+            record("manifest", manifest.map(|m| match m {
+                Some(m) => format!("version {}, {} nodes", m.version, m.nodes.len()),
+                None => "none yet, a new filesystem starts empty".to_string(),
+            }));
+        }
+        Err(e) => record("backing", Err(e)),
+    }
+    if let Some(path) = &args.tunables {
+        let limits = ThrottleLimits { read_bps: args.max_read_bps, write_bps: args.max_write_bps, iops: args.max_iops };
+        record("tunables", limits.load(path).map(|l| l.to_string()).map_err(|e| (EXIT_FAILURE, e)));
+    }
+    let fuse = std::fs::OpenOptions::new().read(true).write(true).open("/dev/fuse");
+    record("fuse", fuse.map(|_| "/dev/fuse is accessible".to_string()).map_err(|e| (EXIT_FAILURE, format!("/dev/fuse: {}", e))));
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&results).unwrap());
+    } else {
+        for r in &results {
+            println!("{:<4}  {:<10}  {}", if r.ok { "ok" } else { "FAIL" }, r.check, r.message);
+        }
+    }
+    status
+}
 
-    fuser::mount2(
+fn main() {
+    let args = Cli::parse();
+    if args.check_only {
+        std::process::exit(check_only(&args));
+    }
+    if let Err((code, msg)) = validate_mountpoint(&args.mountpoint, args.allow_nonempty) {
+        eprintln!("imgfs: {}", msg);
+        std::process::exit(code);
+    }
+    // Before any thread exists, so all of them inherit the mask.
+    signals::block_signals().expect("block signals");
+    let (backing, backend) = open_backing(&args, true).unwrap_or_else(|(code, msg)| {
+        eprintln!("imgfs: {}", msg);
+        std::process::exit(code);
+    });
+    let read_only = backend.read_only();
+
+    let cli_limits = ThrottleLimits { read_bps: args.max_read_bps, write_bps: args.max_write_bps, iops: args.max_iops };
+    let throttle = match &args.tunables {
+        Some(path) => cli_limits.load(path).unwrap_or_else(|e| {
+            eprintln!("imgfs: tunables: {}", e);
+            std::process::exit(EXIT_FAILURE);
+        }),
+        None => cli_limits,
+    };
+    let opts = MountOptions {
+        trash: args.trash,
+        trash_retention: Duration::from_secs(args.trash_retention),
+        quota_limits: args.quotas.into_iter().collect(),
+        throttle,
+        retry: RetryPolicy {
+            retries: args.io_retries,
+            base_delay: Duration::from_millis(args.io_retry_delay_ms),
+        },
+        casefold: args.casefold,
+        normalize: args.normalize,
+        io_size: args
+            .blksize
+            .unwrap_or((BLOCK_BYTES as u32).clamp(MIN_IO_SIZE, MAX_IO_SIZE)),
+        data_mode: args.data,
+        max_file_size: args.max_file_size,
+        flush_on_close: args.flush_on_close,
+        rename_barrier: !args.no_rename_barrier,
+        max_dirty_blocks: (args.max_dirty / BLOCK_BYTES as u64).max(1) as usize,
+        io_stats: args.io_stats || args.audit,
+        audit: args.audit,
+    };
+    let tracer = args
+        .trace_file
+        .as_deref()
+        .map(|p| Tracer::open(p).expect("open trace file"));
+    let fs = ImageFS::new(backing, backend, opts.clone(), tracer).expect("load backing dir");
+    if let Some(mode) = args.gc {
+        let (count, bytes) = fs.state.lock().unwrap().collect_orphans(mode).expect("garbage collect backing dir");
+        let done = if mode == GcMode::Move { "moved to lost+found" } else { "deleted" };
+        eprintln!("imgfs: gc: {} orphaned block images ({} bytes) {}", count, bytes, done);
+    }
+    fs.spawn_tail_flusher();
+    let state = Arc::clone(&fs.state);
+    let tunables = args.tunables.clone().map(|path| Tunables { path, base: cli_limits, throttle: Arc::clone(&fs.throttle) });
+    let backing_dir = fs.state.lock().unwrap().backing.clone();
+
+    let mut session = fuser::Session::new(
         fs,
-        mountpoint,
-        &[
-            MountOption::FSName("imgfs".to_string()),
-            MountOption::AutoUnmount,
-            MountOption::RW,
-        ],
+        &args.mountpoint,
+        &mount_options(args.name.as_deref(), read_only),
     ).expect("mount failed");
-}
\ No newline at end of file
+    let now = SystemTime::now();
+    let record = MountRecord {
+        id: runtime::fingerprint(&backing_dir),
+        name: args.name.clone(),
+        backing_dir: std::fs::canonicalize(&backing_dir).unwrap_or(backing_dir),
+        mountpoint: std::path::absolute(&args.mountpoint).unwrap_or_else(|_| PathBuf::from(&args.mountpoint)),
+        pid: std::process::id(),
+        started_at: now,
+        heartbeat: now,
+        health: "ok".to_string(),
+        options: std::env::args().skip(1).collect(),
+    };
+    // Orchestration is a convenience; failing to register is no reason
+    // not to serve the mount.
+    if let Err(e) = record.save() {
+        eprintln!("imgfs: cannot write {}: {}", record.path().display(), e);
+    }
+    let record = Arc::new(Mutex::new(Some(record)));
+    ImageFS::spawn_heartbeat(Arc::clone(&state), Arc::clone(&record));
+    if opts.trash {
+        ImageFS::spawn_trash_reaper(Arc::clone(&state), session.notifier());
+    }
+    ImageFS::spawn_signal_handler(
+        state,
+        session.unmount_callable(),
+        PathBuf::from(&args.mountpoint),
+        Duration::from_secs(args.unmount_timeout),
+        args.force_unmount,
+        tunables,
+    );
+    session.run().expect("filesystem session failed");
+    let taken = record.lock().unwrap().take();
+    if let Some(r) = taken {
+        let _ = std::fs::remove_file(r.path());
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const ROOT: Caller = (0, 0);
+
+    /// Backing directory of one test, removed when the test ends.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            let name = format!("bwfs-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_options() -> MountOptions {
+        MountOptions {
+            trash: false,
+            trash_retention: Duration::ZERO,
+            quota_limits: HashMap::new(),
+            throttle: ThrottleLimits::default(),
+            retry: RetryPolicy { retries: 0, base_delay: Duration::ZERO },
+            casefold: false,
+            normalize: None,
+            io_size: MIN_IO_SIZE,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            data_mode: DataMode::Writeback,
+            flush_on_close: false,
+            rename_barrier: true,
+            max_dirty_blocks: 64,
+            io_stats: false,
+            audit: false,
+        }
+    }
+
+    /// An empty filesystem over a fresh directory backend, with the root
+    /// owned by root and writable by everyone.
+    fn new_state(opts: MountOptions) -> (TempDir, FilesystemState) {
+        let dir = TempDir::new();
+        let (backing, backend) = backend::open(dir.0.to_str().unwrap(), true).unwrap();
+        let mut st = FilesystemState::new(backing, backend, opts).unwrap();
+        let root = st.nodes.get_mut(&1).unwrap();
+        (root.uid, root.gid, root.perm) = (0, 0, 0o777);
+        (dir, st)
+    }
+
+    fn create(st: &mut FilesystemState, name: &str, is_dir: bool) -> Inode {
+        st.create_node(1, OsStr::new(name), is_dir, if is_dir { 0o755 } else { 0o644 }, ROOT).unwrap()
+    }
+
+    #[test]
+    fn operations_on_the_wrong_node_type_fail() {
+        let (_dir, mut st) = new_state(test_options());
+        let file = create(&mut st, "file", false);
+        let dir = create(&mut st, "dir", true);
+        type Op = fn(&mut FilesystemState, Inode) -> Result<(), c_int>;
+        // Errno on a file, a directory and a missing inode; 0 is success.
+        let table: [(&str, Op, c_int, c_int, c_int); 5] = [
+            ("read", |st, ino| st.read_at(ino, 0, 1).map(drop), 0, EISDIR, ENOENT),
+            ("write", |st, ino| st.write_at(ino, 0, b"x").map(drop), 0, EISDIR, ENOENT),
+            ("truncate", |st, ino| st.truncate(ino, 0), 0, EISDIR, ENOENT),
+            ("opendir", |st, ino| st.open_dir_handle(ino).map(drop), ENOTDIR, 0, ENOENT),
+            ("create", |st, ino| st.create_node(ino, OsStr::new("x"), false, 0o644, ROOT).map(drop), ENOTDIR, 0, ENOENT),
+        ];
+        for (op, f, on_file, on_dir, on_missing) in table {
+            for (ino, want, what) in [(file, on_file, "file"), (dir, on_dir, "directory"), (999, on_missing, "missing")] {
+                assert_eq!(f(&mut st, ino).err().unwrap_or(0), want, "{} on {}", op, what);
+            }
+        }
+    }
+
+    #[test]
+    fn removing_or_replacing_the_wrong_node_type_fails() {
+        let (_dir, mut st) = new_state(test_options());
+        create(&mut st, "file", false);
+        create(&mut st, "dir", true);
+        let name = OsStr::new;
+        assert_eq!(st.unlink(1, name("dir"), ROOT), Err(EPERM));
+        assert_eq!(st.rmdir(1, name("file"), ROOT), Err(ENOTDIR));
+        assert_eq!(st.rename(1, name("file"), 1, name("dir"), ROOT), Err(EISDIR));
+        assert_eq!(st.rename(1, name("dir"), 1, name("file"), ROOT), Err(ENOTDIR));
+        assert_eq!(st.rename(1, name("dir"), st.lookup_path("/dir").unwrap(), name("sub"), ROOT), Err(EINVAL));
+        assert!(st.lookup_path("/file").is_some() && st.lookup_path("/dir").is_some());
+    }
+
+    #[test]
+    fn rename_over_a_file_frees_the_replaced_node() {
+        let (_dir, mut st) = new_state(test_options());
+        let new = create(&mut st, "new", false);
+        let old = create(&mut st, "old", false);
+        st.write_at(old, 0, b"old contents").unwrap();
+        st.sync_all().unwrap();
+        let old_block = st.nodes[&old].blocks[&0].clone();
+        assert!(st.backend.exists(&old_block));
+        let used = st.quotas[&0].used_blocks;
+
+        st.rename(1, OsStr::new("new"), 1, OsStr::new("old"), ROOT).unwrap();
+        assert_eq!(st.lookup_path("/old"), Some(new));
+        assert_eq!(st.lookup_path("/new"), None);
+        assert!(!st.nodes.contains_key(&old));
+        // The barrier commits the manifest, which deletes the block.
+        assert!(!st.backend.exists(&old_block));
+        assert_eq!(st.quotas[&0].used_blocks, used - 1);
+    }
+
+    #[test]
+    fn access_is_checked_against_the_callers_class() {
+        let mut node = FileNode::new(2, "/f", false, 0o640);
+        (node.uid, node.gid) = (1000, 100);
+        assert_eq!(node.check_access((1000, 1), R_OK | W_OK), Ok(()));
+        assert_eq!(node.check_access((1000, 1), X_OK), Err(EACCES));
+        assert_eq!(node.check_access((2000, 100), R_OK), Ok(()));
+        assert_eq!(node.check_access((2000, 100), W_OK), Err(EACCES));
+        assert_eq!(node.check_access((2000, 1), R_OK), Err(EACCES));
+        // The owner class applies even when it grants less than the group.
+        node.perm = 0o070;
+        assert_eq!(node.check_access((1000, 100), R_OK), Err(EACCES));
+        // Root passes everything but executing a file without any x bit.
+        assert_eq!(node.check_access(ROOT, R_OK | W_OK), Ok(()));
+        assert_eq!(node.check_access(ROOT, X_OK), Ok(()));
+        node.perm = 0o600;
+        assert_eq!(node.check_access(ROOT, X_OK), Err(EACCES));
+        node.is_dir = true;
+        assert_eq!(node.check_access(ROOT, X_OK), Ok(()));
+    }
+
+    #[test]
+    fn creating_needs_write_and_search_on_the_parent() {
+        let (_dir, mut st) = new_state(test_options());
+        let dir = create(&mut st, "dir", true);
+        let user = (1000, 1000);
+        assert_eq!(st.create_node(dir, OsStr::new("f"), false, 0o644, user), Err(EACCES));
+        st.nodes.get_mut(&dir).unwrap().perm = 0o776;
+        assert_eq!(st.create_node(dir, OsStr::new("f"), false, 0o644, user), Err(EACCES));
+        st.nodes.get_mut(&dir).unwrap().perm = 0o777;
+        let ino = st.create_node(dir, OsStr::new("f"), false, 0o644, user).unwrap();
+        assert_eq!((st.nodes[&ino].uid, st.nodes[&ino].gid), user);
+        assert_eq!(st.unlink(dir, OsStr::new("f"), (2000, 2000)), Ok(()));
+    }
+
+    #[test]
+    fn chmod_is_for_the_owner_and_root() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "f", false);
+        let node = st.nodes.get_mut(&ino).unwrap();
+        (node.uid, node.gid) = (1000, 100);
+        assert_eq!(st.chmod(ino, 0o777, (2000, 100)), Err(EPERM));
+        assert_eq!(st.chmod(ino, 0o2755, (1000, 100)), Ok(()));
+        assert_eq!(st.nodes[&ino].perm, 0o2755);
+        // The owner outside the file's group loses setgid.
+        assert_eq!(st.chmod(ino, 0o2755, (1000, 1000)), Ok(()));
+        assert_eq!(st.nodes[&ino].perm, 0o755);
+        assert_eq!(st.chmod(ino, 0o2700, ROOT), Ok(()));
+        assert_eq!(st.nodes[&ino].perm, 0o2700);
+        assert_eq!(st.chmod(999, 0o644, ROOT), Err(ENOENT));
+    }
+
+    #[test]
+    fn explicit_times_need_the_owner_and_now_needs_write() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "f", false);
+        let node = st.nodes.get_mut(&ino).unwrap();
+        (node.uid, node.gid, node.perm) = (1000, 100, 0o664);
+        let (past, now) = (UNIX_EPOCH + Duration::from_secs(1000), SystemTime::now());
+        let explicit = Some(fuser::TimeOrNow::SpecificTime(past));
+        let to_now = Some(fuser::TimeOrNow::Now);
+
+        assert_eq!(st.set_times(ino, explicit, explicit, None, (2000, 100), now), Err(EPERM));
+        assert_eq!(st.set_times(ino, None, None, Some(past), (2000, 100), now), Err(EPERM));
+        assert_eq!(st.set_times(ino, to_now, to_now, None, (2000, 2000), now), Err(EACCES));
+        assert_eq!(st.set_times(ino, to_now, to_now, None, (2000, 100), now), Ok(()));
+        assert_eq!(st.nodes[&ino].mtime, now);
+        assert_eq!(st.set_times(ino, explicit, explicit, Some(past), (1000, 1), now), Ok(()));
+        assert_eq!((st.nodes[&ino].mtime, st.nodes[&ino].crtime), (past, past));
+        st.nodes.get_mut(&ino).unwrap().perm = 0o444;
+        assert_eq!(st.set_times(ino, explicit, to_now, None, ROOT, now), Ok(()));
+    }
+
+    #[test]
+    fn truncate_zeroes_the_rest_of_the_tail_block() {
+        // Once with the tail still dirty, once with it written back.
+        for saved in [false, true] {
+            let (_dir, mut st) = new_state(test_options());
+            let ino = create(&mut st, "f", false);
+            st.write_at(ino, 0, &[0xaa; 100]).unwrap();
+            if saved {
+                st.sync_all().unwrap();
+                st.nodes.get_mut(&ino).unwrap().dirty.clear();
+            }
+            st.truncate(ino, 10).unwrap();
+            st.write_at(ino, 90, b"end").unwrap();
+
+            let data = st.read_at(ino, 0, 200).unwrap();
+            assert_eq!(data.len(), 93);
+            assert_eq!(&data[..10], &[0xaa; 10]);
+            assert!(data[10..90].iter().all(|&b| b == 0), "stale bytes after truncate (saved: {})", saved);
+            assert_eq!(&data[90..], b"end");
+        }
+    }
+
+    #[test]
+    fn seek_data_and_hole_walk_the_block_map() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "sparse", false);
+        let b = BLOCK_BYTES as u64;
+        // Data in blocks 0 and 2, holes in 1 and 3.
+        st.write_at(ino, 0, b"a").unwrap();
+        st.write_at(ino, 2 * b as i64, b"b").unwrap();
+        st.truncate(ino, 4 * b).unwrap();
+        let node = &st.nodes[&ino];
+        let seek = |offset, want_data| node.seek_data_hole(offset, want_data, &*st.backend);
+
+        assert_eq!(seek(0, true), Ok(0));
+        assert_eq!(seek(0, false), Ok(b));
+        assert_eq!(seek(b + 5, false), Ok(b + 5));
+        assert_eq!(seek(b + 5, true), Ok(2 * b));
+        assert_eq!(seek(2 * b, false), Ok(3 * b));
+        assert_eq!(seek(3 * b, true), Err(libc::ENXIO));
+        assert_eq!(seek(3 * b, false), Ok(3 * b));
+        assert_eq!(seek(4 * b, false), Err(libc::ENXIO));
+
+        // Written back and evicted, the blocks are still data.
+        st.sync_all().unwrap();
+        st.nodes.get_mut(&ino).unwrap().dirty.clear();
+        let node = &st.nodes[&ino];
+        assert_eq!(node.seek_data_hole(0, false, &*st.backend), Ok(b));
+        assert_eq!(node.seek_data_hole(b, true, &*st.backend), Ok(2 * b));
+    }
+
+    #[test]
+    fn sparse_writes_allocate_only_the_blocks_they_touch() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "sparse", false);
+        let gib = 1 << 30;
+        assert_eq!(st.write_at(ino, gib, b"x"), Ok(1));
+        let node = &st.nodes[&ino];
+        assert_eq!(node.size, gib as u64 + 1);
+        assert_eq!(node.blocks.keys().copied().collect::<Vec<_>>(), [gib as usize / BLOCK_BYTES]);
+        assert_eq!(st.read_at(ino, 0, 4).unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn files_past_the_size_limit_fail_with_efbig() {
+        let mut opts = test_options();
+        opts.quota_limits.insert(0, 16);
+        opts.max_file_size = 8 * BLOCK_BYTES as u64;
+        let (_dir, mut st) = new_state(opts);
+        let ino = create(&mut st, "big", false);
+        let max = 8 * BLOCK_BYTES as i64;
+        assert_eq!(st.write_at(ino, 1 << 30, b"x"), Err(libc::EFBIG));
+        assert_eq!(st.write_at(ino, max, b"x"), Err(libc::EFBIG));
+        assert_eq!(st.write_at(ino, i64::MAX, b"x"), Err(libc::EFBIG));
+        assert_eq!(st.truncate(ino, max as u64 + 1), Err(libc::EFBIG));
+        assert!(st.nodes[&ino].blocks.is_empty());
+        assert_eq!(st.quotas[&0].used_blocks, 0);
+        assert_eq!(st.write_at(ino, max - 1, b"x"), Ok(1));
+        assert_eq!(st.truncate(ino, max as u64), Ok(()));
+
+        // A quota smaller than --max-file-size caps the size too.
+        st.opts.max_file_size = DEFAULT_MAX_FILE_SIZE;
+        assert_eq!(st.max_file_size(0), 16 * BLOCK_BYTES as u64);
+        assert_eq!(st.write_at(ino, 16 * BLOCK_BYTES as i64, b"x"), Err(libc::EFBIG));
+    }
+
+    #[test]
+    fn omitted_times_are_left_alone() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "f", false);
+        let (t1, t2) = (UNIX_EPOCH + Duration::from_secs(1000), UNIX_EPOCH + Duration::from_secs(2000));
+        let at = |t| Some(fuser::TimeOrNow::SpecificTime(t));
+        st.set_times(ino, at(t1), at(t1), None, ROOT, t2).unwrap();
+        let crtime = st.nodes[&ino].crtime;
+
+        // touch -a, then touch -m.
+        st.set_times(ino, at(t2), None, None, ROOT, t2).unwrap();
+        assert_eq!((st.nodes[&ino].atime, st.nodes[&ino].mtime), (t2, t1));
+        let t3 = t2 + Duration::from_secs(1000);
+        st.set_times(ino, None, Some(fuser::TimeOrNow::Now), None, ROOT, t3).unwrap();
+        assert_eq!((st.nodes[&ino].atime, st.nodes[&ino].mtime), (t2, t3));
+        st.set_times(ino, None, None, None, ROOT, t1).unwrap();
+        assert_eq!((st.nodes[&ino].atime, st.nodes[&ino].mtime), (t2, t3));
+        assert_eq!(st.nodes[&ino].crtime, crtime);
+    }
+
+    #[test]
+    fn readdir_continues_from_the_returned_offset() {
+        let (_dir, mut st) = new_state(test_options());
+        for name in ["c", "a", "d", "b"] {
+            create(&mut st, name, false);
+        }
+        let fh = st.open_dir_handle(1).unwrap();
+        // New entries after opendir stay out of the handle's snapshot.
+        create(&mut st, "aa", false);
+        let names = |st: &FilesystemState, offset| -> Vec<(i64, String)> {
+            st.read_dir_handle(fh, offset).unwrap().map(|(next, e)| (next, e.name.clone())).collect()
+        };
+
+        let all = names(&st, 0);
+        let expected = [".", "..", "a", "b", "c", "d"];
+        assert_eq!(all.iter().map(|(_, n)| n.as_str()).collect::<Vec<_>>(), expected);
+        // Resuming after any entry yields exactly the ones after it.
+        for (i, (next, _)) in all.iter().enumerate() {
+            assert_eq!(names(&st, *next), all[i + 1..]);
+        }
+        assert_eq!(names(&st, -1), all);
+        assert!(names(&st, 100).is_empty());
+
+        st.dir_handles.remove(&fh);
+        assert!(matches!(st.read_dir_handle(fh, 0), Err(libc::EBADF)));
+        let fh = st.open_dir_handle(1).unwrap();
+        assert_eq!(st.read_dir_handle(fh, 2).unwrap().next().unwrap().1.name, "a");
+        assert_eq!(st.read_dir_handle(fh, 3).unwrap().next().unwrap().1.name, "aa");
+    }
+
+    #[test]
+    fn created_modes_follow_the_umask_and_setgid_directories() {
+        assert_eq!(create_perm(libc::S_IFREG | 0o666, 0o022), 0o644);
+        assert_eq!(create_perm(libc::S_IFDIR | 0o777, 0o077), 0o700);
+        assert_eq!(create_perm(0o4755, 0o022), 0o4755);
+        assert_eq!(create_perm(0o666, 0), 0o666);
+
+        let (_dir, mut st) = new_state(test_options());
+        let shared = create(&mut st, "shared", true);
+        let node = st.nodes.get_mut(&shared).unwrap();
+        (node.gid, node.perm) = (100, 0o2777);
+        let user = (1000, 1000);
+        // Children take the directory's group; subdirectories also its setgid.
+        let sub = st.create_node(shared, OsStr::new("sub"), true, create_perm(0o777, 0o022), user).unwrap();
+        assert_eq!((st.nodes[&sub].gid, st.nodes[&sub].perm), (100, 0o2755));
+        let file = st.create_node(shared, OsStr::new("f"), false, create_perm(0o2666, 0o022), user).unwrap();
+        assert_eq!((st.nodes[&file].gid, st.nodes[&file].perm), (100, 0o644));
+        let file = st.create_node(shared, OsStr::new("g"), false, 0o2644, (1000, 100)).unwrap();
+        assert_eq!(st.nodes[&file].perm, 0o2644);
+        // Outside a setgid directory the creator's group applies.
+        let file = st.create_node(1, OsStr::new("h"), false, 0o2644, user).unwrap();
+        assert_eq!((st.nodes[&file].gid, st.nodes[&file].perm), (1000, 0o2644));
+    }
+
+    #[test]
+    fn a_file_moved_out_of_the_trash_is_kept() {
+        let mut opts = test_options();
+        opts.trash = true;
+        let (_dir, mut st) = new_state(opts);
+        let ino = create(&mut st, "f", false);
+        st.write_at(ino, 0, b"keep me").unwrap();
+        st.sync_all().unwrap();
+        let block = st.nodes[&ino].blocks[&0].clone();
+
+        st.unlink(1, OsStr::new("f"), ROOT).unwrap();
+        let trash = st.lookup_path(TRASH_DIR).unwrap();
+        let trashed = st.nodes[&ino].name.rsplit('/').next().unwrap().to_string();
+        assert_eq!(st.lookup_path(&format!("{}/{}", TRASH_DIR, trashed)), Some(ino));
+        st.rename(trash, OsStr::new(&trashed), 1, OsStr::new("f"), ROOT).unwrap();
+
+        let (_, freed) = st.free_trash(Duration::ZERO);
+        assert!(freed.is_empty());
+        st.sync_all().unwrap();
+        assert_eq!(st.lookup_path("/f"), Some(ino));
+        assert!(st.backend.exists(&block));
+        st.nodes.get_mut(&ino).unwrap().dirty.clear();
+        assert_eq!(st.read_at(ino, 0, 7).unwrap(), b"keep me");
+    }
+
+    #[test]
+    fn empty_trash_frees_every_trashed_file() {
+        let mut opts = test_options();
+        opts.trash = true;
+        opts.trash_retention = Duration::from_secs(3600);
+        let (_dir, mut st) = new_state(opts);
+        let gone: Vec<Inode> = ["a", "b"].iter().map(|name| create(&mut st, name, false)).collect();
+        for name in ["a", "b"] {
+            st.unlink(1, OsStr::new(name), ROOT).unwrap();
+        }
+        let trash = st.lookup_path(TRASH_DIR).unwrap();
+        // Nothing is old enough for the reaper yet.
+        assert!(st.expire_trash().1.is_empty());
+        assert_eq!(st.empty_trash(1, ROOT), Err(libc::ENOTSUP));
+        assert_eq!(st.empty_trash(trash, (1000, 1000)), Err(EPERM));
+
+        assert_eq!(st.empty_trash(trash, ROOT), Ok(()));
+        assert!(gone.iter().all(|ino| !st.nodes.contains_key(ino)));
+        assert!(st.trashed.is_empty());
+        assert_eq!(st.dir_listing(trash).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn handles_are_resolved_before_use() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "f", false);
+        let fh = st.open_handle(ino, libc::O_RDWR);
+        assert_eq!(st.resolve_handle(fh, ino), Ok(ino));
+        assert_eq!(st.resolve_handle(fh + 100, ino), Err(libc::EBADF));
+        // The handle's inode wins over the one the kernel passed.
+        let other = create(&mut st, "g", false);
+        assert_eq!(st.resolve_handle(fh, other), Ok(ino));
+
+        // Unlink and create a file under the same name: the old handle is stale.
+        st.unlink(1, OsStr::new("f"), ROOT).unwrap();
+        let new = create(&mut st, "f", false);
+        assert_ne!(new, ino);
+        assert_eq!(st.resolve_handle(fh, ino), Err(libc::ESTALE));
+        assert_eq!(st.resolve_handle(fh, new), Err(libc::ESTALE));
+        st.handles.remove(&fh);
+        assert_eq!(st.resolve_handle(fh, new), Err(libc::EBADF));
+    }
+
+    #[test]
+    fn rmdir_removes_only_empty_directories() {
+        let (_dir, mut st) = new_state(test_options());
+        let dir = create(&mut st, "dir", true);
+        create(&mut st, "file", false);
+        let nlink = |st: &FilesystemState, ino| st.nodes[&ino].attr(MIN_IO_SIZE).nlink;
+        assert_eq!(nlink(&st, 1), 3);
+
+        assert_eq!(st.rmdir(1, OsStr::new("missing"), ROOT), Err(ENOENT));
+        assert_eq!(st.rmdir(1, OsStr::new("file"), ROOT), Err(ENOTDIR));
+        // Removed entries between live ones leave it non-empty until the last goes.
+        for name in ["a", "b", "c"] {
+            st.create_node(dir, OsStr::new(name), false, 0o644, ROOT).unwrap();
+        }
+        st.unlink(dir, OsStr::new("a"), ROOT).unwrap();
+        st.unlink(dir, OsStr::new("c"), ROOT).unwrap();
+        assert_eq!(st.rmdir(1, OsStr::new("dir"), ROOT), Err(libc::ENOTEMPTY));
+        st.unlink(dir, OsStr::new("b"), ROOT).unwrap();
+        let sub = st.create_node(dir, OsStr::new("sub"), true, 0o755, ROOT).unwrap();
+        assert_eq!(nlink(&st, dir), 3);
+        assert_eq!(st.rmdir(1, OsStr::new("dir"), ROOT), Err(libc::ENOTEMPTY));
+
+        assert_eq!(st.rmdir(dir, OsStr::new("sub"), ROOT), Ok(()));
+        assert!(!st.nodes.contains_key(&sub));
+        assert_eq!(nlink(&st, dir), 2);
+        assert_eq!(st.rmdir(1, OsStr::new("dir"), ROOT), Ok(()));
+        assert_eq!(st.lookup_path("/dir"), None);
+        assert_eq!(nlink(&st, 1), 2);
+    }
+
+    #[test]
+    fn quotas_refuse_blocks_past_the_limit() {
+        let mut opts = test_options();
+        opts.quota_limits.insert(1000, 2);
+        let (_dir, mut st) = new_state(opts);
+        let user = (1000, 1000);
+        let b = BLOCK_BYTES as i64;
+        let f = st.create_node(1, OsStr::new("f"), false, 0o644, user).unwrap();
+        let g = st.create_node(1, OsStr::new("g"), false, 0o644, user).unwrap();
+        assert_eq!(st.write_at(f, 0, b"x"), Ok(1));
+        assert_eq!(st.write_at(f, b, b"x"), Ok(1));
+        assert_eq!(st.quotas[&1000].used_blocks, 2);
+        // Rewriting allocated blocks is fine, a third block is not.
+        assert_eq!(st.write_at(f, 10, b"y"), Ok(1));
+        assert_eq!(st.write_at(g, 0, b"x"), Err(EDQUOT));
+        assert!(st.nodes[&g].blocks.is_empty());
+        assert_eq!(st.create_node(1, OsStr::new("h"), false, 0o644, user), Err(EDQUOT));
+        // Directories take no blocks and stay allowed.
+        assert!(st.create_node(1, OsStr::new("d"), true, 0o755, user).is_ok());
+        // Others are not limited.
+        let other = create(&mut st, "other", false);
+        assert_eq!(st.write_at(other, 2 * b, b"x"), Ok(1));
+
+        st.truncate(f, 1).unwrap();
+        assert_eq!(st.quotas[&1000].used_blocks, 1);
+        assert_eq!(st.write_at(g, 0, b"x"), Ok(1));
+        st.unlink(1, OsStr::new("g"), ROOT).unwrap();
+        assert!(st.create_node(1, OsStr::new("h"), false, 0o644, user).is_ok());
+    }
+
+    #[test]
+    fn chown_moves_the_usage_to_the_new_owner() {
+        let mut opts = test_options();
+        opts.quota_limits.insert(2000, 1);
+        let (_dir, mut st) = new_state(opts);
+        let f = create(&mut st, "f", false);
+        st.write_at(f, 0, b"x").unwrap();
+        st.write_at(f, BLOCK_BYTES as i64, b"x").unwrap();
+        assert_eq!(st.quotas[&0].used_blocks, 2);
+
+        assert_eq!(st.chown(f, Some(2000), None, ROOT), Err(EDQUOT));
+        assert_eq!((st.nodes[&f].uid, st.quotas[&0].used_blocks), (0, 2));
+        assert_eq!(st.chown(f, Some(1000), None, ROOT), Ok(()));
+        assert_eq!(st.quotas[&0].used_blocks, 0);
+        assert_eq!(st.quotas[&1000].used_blocks, 2);
+        assert_eq!(st.nodes[&f].uid, 1000);
+    }
+
+    #[test]
+    fn write_amplification_counts_logical_and_encoded_bytes() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "f", false);
+        st.write_at(ino, 0, &[1; 100]).unwrap();
+        st.write_at(ino, 100, &[2; 100]).unwrap();
+        st.sync_all().unwrap();
+        let png = std::fs::metadata(&st.nodes[&ino].blocks[&0]).unwrap().len();
+        let amp = st.nodes[&ino].write_amp;
+        assert_eq!((amp.logical_bytes, amp.encoded_bytes, amp.blocks_encoded), (200, png, 1));
+
+        // Rewriting the same bytes leaves the block as saved: no new encode.
+        st.write_at(ino, 0, &[1; 100]).unwrap();
+        st.sync_all().unwrap();
+        assert_eq!(st.encode_stats.encodes_skipped, 1);
+        // A write straddling two blocks re-encodes both.
+        st.write_at(ino, BLOCK_BYTES as i64 - 50, &[3; 100]).unwrap();
+        st.sync_all().unwrap();
+        let amp = st.nodes[&ino].write_amp;
+        assert_eq!((amp.logical_bytes, amp.blocks_encoded), (400, 3));
+        assert_eq!(st.encode_stats.amp.blocks_encoded, 3);
+        assert_eq!(st.encode_stats.amp.encoded_bytes, amp.encoded_bytes);
+
+        let xattr = String::from_utf8(st.nodes[&ino].virtual_xattr("user.bwfs.write_amp").unwrap()).unwrap();
+        let expected = format!("written 400\nencoded {}\nblocks 3\nratio {:.2}", amp.encoded_bytes, amp.ratio());
+        assert_eq!(xattr, expected);
+    }
+
+    #[test]
+    fn setattr_permission_checks() {
+        let (_dir, mut st) = new_state(test_options());
+        let ino = create(&mut st, "f", false);
+        let node = st.nodes.get_mut(&ino).unwrap();
+        (node.uid, node.gid, node.perm) = (1000, 1000, 0o666);
+        let (owner, other) = ((1000, 1000), (2000, 2000));
+
+        // Anyone may truncate the file, but only root may give it away.
+        assert_eq!(st.check_truncate(ino, None, other), Ok(()));
+        assert_eq!(st.check_chown(ino, Some(2000), None, other), Err(EPERM));
+        assert_eq!(st.check_chown(ino, None, Some(1000), owner), Ok(()));
+        assert_eq!(st.check_chown(ino, None, Some(3000), owner), Err(EPERM));
+        assert_eq!(st.check_chown(ino, Some(2000), Some(3000), ROOT), Ok(()));
+        assert_eq!(st.check_chmod(ino, other), Err(EPERM));
+        assert_eq!(st.check_chmod(ino, owner), Ok(()));
+        assert_eq!(st.check_chmod(999, ROOT), Err(ENOENT));
+
+        // ftruncate through a handle opened for writing needs no write bit.
+        st.nodes.get_mut(&ino).unwrap().perm = 0o444;
+        let rw = st.open_handle(ino, libc::O_RDWR);
+        let ro = st.open_handle(ino, libc::O_RDONLY);
+        assert_eq!(st.check_truncate(ino, None, owner), Err(EACCES));
+        assert_eq!(st.check_truncate(ino, Some(ro), owner), Err(EACCES));
+        assert_eq!(st.check_truncate(ino, Some(rw), owner), Ok(()));
+        // A handle on another file does not count.
+        let g = st.create_node(1, OsStr::new("g"), false, 0o444, owner).unwrap();
+        assert_eq!(st.check_truncate(g, Some(rw), owner), Err(EACCES));
+    }
+
+    #[test]
+    fn named_mounts_get_distinct_sources() {
+        let fsname = |name| match &mount_options(name, false)[0] {
+            MountOption::FSName(source) => source.clone(),
+            other => panic!("expected FSName first, got {:?}", other),
+        };
+        assert_eq!(fsname(Some("photos")), "imgfs:photos");
+        assert_eq!(fsname(Some("backups")), "imgfs:backups");
+        assert_eq!(fsname(None), "imgfs");
+        assert!(mount_options(None, true).contains(&MountOption::RO));
+        assert!(parse_name("bad name").is_err());
+        assert!(parse_name("").is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Metadata manifest for ImageFS.
+//!
+//! The block PNGs only hold file contents; names, sizes, ownership and the
+//! mapping from files to blocks live in `manifest.json` next to them in the
+//! backing directory. The manifest is rewritten on fsync and at unmount and
+//! read back when the filesystem starts, so a backing directory can be
+//! mounted again (or copied to another node) without losing its tree.
+//!
+//! The file is replaced atomically: it is written to a temporary name and
+//! renamed over the old one, so a crash leaves either the old or the new
+//! manifest, never a torn one.
+//!
+//! Block images of deleted or truncated files are only removed once a
+//! manifest that no longer references them is on disk, so the old
+//! manifest never points at images that are already gone. The manifest
+//! lists those images until they are removed, and mounting completes any
+//! removal a crash interrupted.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::backend::BlockBackend;
+
+/// File name of the manifest inside the backing directory.
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Current manifest format version.
+///
+/// - 1: initial format
+/// - 2: holes in `ManifestNode::blocks` are stored as empty names
+pub const MANIFEST_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Next inode number to hand out.
+    pub next_ino: u64,
+    /// Next block image id to hand out.
+    pub next_block: u64,
+    pub nodes: Vec<ManifestNode>,
+    /// Block images no longer referenced, to delete once this is saved.
+    #[serde(default)]
+    pub pending_deletes: Vec<String>,
+}
+
+/// One file or directory, keyed by its full path.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestNode {
+    pub ino: u64,
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub perm: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    /// Birth time; absent in manifests written before it was tracked.
+    #[serde(default)]
+    pub crtime: Option<SystemTime>,
+    /// Block image file names, relative to the backing directory, by block
+    /// index; "" marks a hole.
+    pub blocks: Vec<String>,
+    /// Set for files sitting in the trash.
+    #[serde(default)]
+    pub trashed_at: Option<SystemTime>,
+}
+
+impl Manifest {
+    /// Read the manifest from `dir`, or `None` if there is none yet.
+    pub fn load(backend: &dyn BlockBackend, dir: &Path) -> io::Result<Option<Self>> {
+        let path = dir.join(MANIFEST_NAME);
+        if !backend.exists(&path) {
+            return Ok(None);
+        }
+        let bytes = backend.load(&path)?;
+        let manifest: Manifest = serde_json::from_slice(&bytes).map_err(io::Error::other)?;
+        if manifest.version > MANIFEST_VERSION {
+            return Err(io::Error::other(format!(
+                "manifest version {} is newer than supported version {}",
+                manifest.version, MANIFEST_VERSION
+            )));
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Atomically replace the manifest in `dir`.
+    pub fn save(&self, backend: &dyn BlockBackend, dir: &Path) -> io::Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_NAME));
+        let bytes = serde_json::to_vec(self).map_err(io::Error::other)?;
+        backend.save(&tmp, &bytes)?;
+        backend.rename(&tmp, &dir.join(MANIFEST_NAME))
+    }
+}
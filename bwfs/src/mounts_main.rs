@@ -0,0 +1,90 @@
+//! bwfs_mounts: list the running bwfs mounts from their runtime records.
+//!
+//! Each record is checked against the process table and its heartbeat;
+//! records left behind by a daemon that died are flagged as stale, and
+//! `--prune` removes them. Unreadable records are reported as invalid.
+//!
+//! Usage:
+//!     bwfs_mounts [--json] [--prune]
+
+use clap::Parser;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+// Writing records is left to the daemon.
+#[allow(dead_code)]
+mod runtime;
+
+use runtime::MountRecord;
+
+#[derive(Parser)]
+struct Cli {
+    /// Print the records as JSON instead of a table.
+    #[arg(long)]
+    json: bool,
+
+    /// Remove stale and unreadable records.
+    #[arg(long)]
+    prune: bool,
+}
+
+#[derive(Serialize)]
+struct Listed {
+    file: PathBuf,
+    /// None for a file that is not a valid record.
+    record: Option<MountRecord>,
+    /// Why the record is stale or invalid; None for a live mount.
+    problem: Option<String>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let records = match runtime::list() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("bwfs_mounts: cannot read {}: {}", runtime::runtime_dir().display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let now = SystemTime::now();
+    let listed: Vec<Listed> = records
+        .into_iter()
+        .map(|(file, record)| match record {
+            Ok(r) => Listed { file, problem: r.stale_reason(now).map(|s| format!("stale: {}", s)), record: Some(r) },
+            Err(e) => Listed { file, record: None, problem: Some(format!("invalid: {}", e)) },
+        })
+        .collect();
+
+    if cli.prune {
+        for l in listed.iter().filter(|l| l.problem.is_some()) {
+            if let Err(e) = std::fs::remove_file(&l.file) {
+                eprintln!("bwfs_mounts: cannot remove {}: {}", l.file.display(), e);
+            }
+        }
+    }
+
+    if cli.json {
+        println!("{}", serde_json::to_string_pretty(&listed).unwrap());
+        return;
+    }
+    println!("{:<16} {:<12} {:>8} {:<10} {:<24} backing dir", "id", "name", "pid", "health", "mountpoint");
+    for l in &listed {
+        match (&l.record, &l.problem) {
+            (Some(r), problem) => println!(
+                "{:<16} {:<12} {:>8} {:<10} {:<24} {}{}",
+                r.id,
+                r.name.as_deref().unwrap_or("-"),
+                r.pid,
+                r.health,
+                r.mountpoint.display(),
+                r.backing_dir.display(),
+                problem.as_ref().map(|p| format!("  ({})", p)).unwrap_or_default()
+            ),
+            (None, problem) => {
+                println!("{}: {}", l.file.display(), problem.as_deref().unwrap_or_default())
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! Retry with backoff for backing-store IO.
+//!
+//! When the backing directory sits on a network filesystem or a USB disk,
+//! the host sometimes fails a read or write with EIO or EAGAIN even though
+//! the same call succeeds a moment later. A block load or save that fails
+//! that way makes its operation return [`TRANSIENT`], and
+//! [`RetryPolicy::run`] repeats the whole operation with exponential
+//! backoff, only handing back EIO once the attempts are used up. The
+//! state lock is released during each backoff, so one slow disk does not
+//! stall every other caller. Errors that cannot go away by themselves,
+//! such as ENOSPC or a PNG that fails to decode, are EIO immediately.
+
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use libc::c_int;
+
+/// Errno an operation returns for a block IO failure worth retrying.
+/// Never reaches the kernel: `RetryPolicy::run` turns it into EIO.
+pub const TRANSIENT: c_int = libc::EAGAIN;
+
+/// Upper bound for a single backoff sleep.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How often, and how patiently, a failed block IO is retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub retries: u32,
+    /// Sleep before the first retry, doubled for each one after it.
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Lock `state` and run `op` on it, repeating it while it fails with
+    /// [`TRANSIENT`]. Each retry is counted with `count`, and the lock is
+    /// released while sleeping, so `op` must be safe to run again from
+    /// the start. Returns the guard together with the result.
+    pub fn run<'a, S, T>(
+        &self,
+        state: &'a Mutex<S>,
+        count: impl Fn(&mut S),
+        mut op: impl FnMut(&mut S) -> Result<T, c_int>,
+    ) -> (MutexGuard<'a, S>, Result<T, c_int>) {
+        let mut delay = self.base_delay;
+        let mut left = self.retries;
+        let mut guard = state.lock().unwrap();
+        loop {
+            match op(&mut guard) {
+                Err(TRANSIENT) if left > 0 => {
+                    count(&mut guard);
+                    left -= 1;
+                    drop(guard);
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                    guard = state.lock().unwrap();
+                }
+                Err(TRANSIENT) => return (guard, Err(libc::EIO)),
+                res => return (guard, res),
+            }
+        }
+    }
+}
+
+/// Errno for a failed block IO: [`TRANSIENT`] if it may succeed when
+/// repeated, EIO otherwise.
+pub fn errno(e: &io::Error) -> c_int {
+    if is_transient(e) {
+        TRANSIENT
+    } else {
+        libc::EIO
+    }
+}
+
+/// Whether `e` may succeed if the same call is simply repeated.
+fn is_transient(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(code) => matches!(code, libc::EIO | libc::EAGAIN | libc::EINTR | libc::EBUSY | libc::ETIMEDOUT),
+        None => matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+    }
+}
@@ -0,0 +1,113 @@
+//! Runtime records of running mounts, for orchestration tools.
+//!
+//! While mounted, every daemon keeps a small JSON file in the runtime
+//! directory (`$XDG_RUNTIME_DIR/bwfs`, or `/run/bwfs` without one) that
+//! names the backing directory it serves, where it is mounted, its PID
+//! and its health. The heartbeat in it is refreshed every
+//! [`HEARTBEAT_INTERVAL`], and the file is removed on a clean unmount, so
+//! a record whose PID is gone or whose heartbeat stopped belongs to a
+//! daemon that died. `bwfs_mounts` lists and checks the records.
+//!
+//! Records are keyed by a fingerprint of the canonical backing directory
+//! path, which stays the same across remounts of the same directory.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often a running daemon refreshes its heartbeat.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Heartbeats older than this many intervals mark a record as stale.
+pub const STALE_INTERVALS: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+pub struct MountRecord {
+    /// Fingerprint of the backing directory, also the file's stem.
+    pub id: String,
+    /// `--name` of the mount, if given.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub backing_dir: PathBuf,
+    pub mountpoint: PathBuf,
+    pub pid: u32,
+    pub started_at: SystemTime,
+    pub heartbeat: SystemTime,
+    /// "ok", or "degraded" while a background write-back has failed.
+    pub health: String,
+    /// Command-line options the daemon was started with.
+    pub options: Vec<String>,
+}
+
+impl MountRecord {
+    /// Where this record is kept.
+    pub fn path(&self) -> PathBuf {
+        runtime_dir().join(format!("{}.json", self.id))
+    }
+
+    /// Atomically write the record, creating the runtime directory.
+    pub fn save(&self) -> io::Result<()> {
+        let path = self.path();
+        std::fs::create_dir_all(runtime_dir())?;
+        let tmp = path.with_extension("json.tmp");
+        let bytes = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Why the daemon behind this record looks gone, if it does.
+    pub fn stale_reason(&self, now: SystemTime) -> Option<String> {
+        if !Path::new(&format!("/proc/{}", self.pid)).exists() {
+            return Some(format!("pid {} is gone", self.pid));
+        }
+        let age = now.duration_since(self.heartbeat).unwrap_or_default();
+        if age > HEARTBEAT_INTERVAL * STALE_INTERVALS {
+            return Some(format!("no heartbeat for {}s", age.as_secs()));
+        }
+        None
+    }
+}
+
+/// Directory holding the records of running mounts.
+pub fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("bwfs"),
+        _ => PathBuf::from("/run/bwfs"),
+    }
+}
+
+/// Stable fingerprint of a backing directory (FNV-1a of its canonical path).
+pub fn fingerprint(backing_dir: &Path) -> String {
+    let path = std::fs::canonicalize(backing_dir).unwrap_or_else(|_| backing_dir.to_path_buf());
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in path.as_os_str().as_encoded_bytes() {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", h)
+}
+
+/// Every record in the runtime directory. Files that cannot be read or
+/// parsed are returned as errors alongside their path.
+pub fn list() -> io::Result<Vec<(PathBuf, Result<MountRecord, String>)>> {
+    let dir = runtime_dir();
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let record = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|b| serde_json::from_slice(&b).map_err(|e| e.to_string()));
+        records.push((path, record));
+    }
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(records)
+}
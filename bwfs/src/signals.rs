@@ -0,0 +1,52 @@
+//! Signals for the mount daemon.
+//!
+//! By default SIGINT and SIGTERM kill the daemon on the spot and whatever
+//! is still in the dirty maps is lost. Instead, both signals are blocked
+//! in every thread (the mask is inherited, so this must happen before
+//! any thread is spawned) and a dedicated thread picks them up with
+//! `sigwait`, which leaves it free to flush and unmount in an orderly way.
+//! SIGHUP is picked up the same way and reloads the `--tunables` file
+//! instead of hanging up the daemon.
+
+use std::io;
+
+/// A signal taken by [`wait_signal`].
+pub enum Signal {
+    /// SIGINT or SIGTERM, by name.
+    Terminate(&'static str),
+    /// SIGHUP.
+    Reload,
+}
+
+fn daemon_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+/// Block SIGINT, SIGTERM and SIGHUP in the calling thread and all threads
+/// it spawns from now on.
+pub fn block_signals() -> io::Result<()> {
+    let set = daemon_set();
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Wait for the next SIGINT, SIGTERM or SIGHUP.
+pub fn wait_signal() -> Signal {
+    let set = daemon_set();
+    let mut sig = 0;
+    unsafe { libc::sigwait(&set, &mut sig) };
+    match sig {
+        libc::SIGHUP => Signal::Reload,
+        libc::SIGINT => Signal::Terminate("SIGINT"),
+        _ => Signal::Terminate("SIGTERM"),
+    }
+}
@@ -0,0 +1,249 @@
+//! IO throttling for ImageFS.
+//!
+//! All IO against the backing directory is performed by the daemon, so a
+//! process writing without bound through the mount can saturate the disk
+//! and cgroup IO limits never see it. `--max-read-bps`, `--max-write-bps`
+//! and `--max-iops` cap the mount with one token bucket each.
+//!
+//! A bucket holds at most one second of budget. An operation that finds
+//! the bucket short is still admitted, but leaves it in debt and returns
+//! how long the caller must wait; the handler sleeps for that long before
+//! it takes the state lock, so the background flusher and reaper keep
+//! running while a writer is held back.
+//!
+//! The limits are the only settings that can change while mounted. A
+//! `--tunables` file of `key = value` lines is applied over the command
+//! line at mount and again on every SIGHUP; see [`ThrottleLimits::load`].
+
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Keys a tunables file may set.
+const TUNABLES: [&str; 3] = ["max_read_bps", "max_write_bps", "max_iops"];
+
+/// Token bucket refilled continuously at `rate` tokens per second.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self { rate: rate as f64, tokens: rate as f64, last: Instant::now() }
+    }
+
+    /// Take `n` tokens and return how long to wait until the bucket is
+    /// out of debt again.
+    fn take(&mut self, n: u64) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate);
+        self.last = now;
+
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Per-mount throttle limits, each disabled when `None`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThrottleLimits {
+    pub read_bps: Option<u64>,
+    pub write_bps: Option<u64>,
+    pub iops: Option<u64>,
+}
+
+impl ThrottleLimits {
+    fn get_mut(&mut self, key: &str) -> Option<&mut Option<u64>> {
+        match key {
+            "max_read_bps" => Some(&mut self.read_bps),
+            "max_write_bps" => Some(&mut self.write_bps),
+            "max_iops" => Some(&mut self.iops),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<u64> {
+        match key {
+            "max_read_bps" => self.read_bps,
+            "max_write_bps" => self.write_bps,
+            _ => self.iops,
+        }
+    }
+
+    /// Apply the `key = value` lines of a tunables file over `self`.
+    ///
+    /// A value is a number or `none` to lift the limit; keys the file
+    /// leaves out keep their value from `self`. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn apply(mut self, text: &str) -> Result<Self, String> {
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(format!("line {}: expected key = value", n + 1));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            let Some(slot) = self.get_mut(key) else {
+                return Err(format!(
+                    "line {}: unknown or non-reloadable setting `{}`; only max_read_bps, max_write_bps and max_iops can be reloaded",
+                    n + 1,
+                    key
+                ));
+            };
+            *slot = match value {
+                "none" => None,
+                v => Some(v.parse().map_err(|_| format!("line {}: {}: expected a number or `none`", n + 1, key))?),
+            };
+        }
+        Ok(self)
+    }
+
+    /// Read the tunables file at `path` and apply it over `self`.
+    pub fn load(self, path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.apply(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// `key: old -> new` for each limit that `new` changes.
+    pub fn changes(&self, new: &ThrottleLimits) -> Vec<String> {
+        let show = |v: Option<u64>| v.map_or("none".to_string(), |v| v.to_string());
+        TUNABLES
+            .iter()
+            .filter(|k| self.get(k) != new.get(k))
+            .map(|k| format!("{}: {} -> {}", k, show(self.get(k)), show(new.get(k))))
+            .collect()
+    }
+}
+
+impl fmt::Display for ThrottleLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |v: Option<u64>| v.map_or("none".to_string(), |v| v.to_string());
+        let settings: Vec<String> = TUNABLES.iter().map(|k| format!("{} = {}", k, show(self.get(k)))).collect();
+        f.write_str(&settings.join(", "))
+    }
+}
+
+/// Usage counters, reported at unmount.
+#[derive(Default)]
+pub struct ThrottleStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub ops: u64,
+    /// Operations that had to sleep.
+    pub throttled_ops: u64,
+    /// Total time spent sleeping.
+    pub throttled_for: Duration,
+}
+
+pub struct Throttle {
+    limits: ThrottleLimits,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+    iops: Option<TokenBucket>,
+    pub stats: ThrottleStats,
+}
+
+impl Throttle {
+    pub fn new(limits: ThrottleLimits) -> Self {
+        Self {
+            limits,
+            read: limits.read_bps.map(TokenBucket::new),
+            write: limits.write_bps.map(TokenBucket::new),
+            iops: limits.iops.map(TokenBucket::new),
+            stats: ThrottleStats::default(),
+        }
+    }
+
+    pub fn limits(&self) -> ThrottleLimits {
+        self.limits
+    }
+
+    /// Switch to `limits`. A bucket whose rate changes starts over full;
+    /// the others keep their state.
+    pub fn set_limits(&mut self, limits: ThrottleLimits) {
+        if limits.read_bps != self.limits.read_bps {
+            self.read = limits.read_bps.map(TokenBucket::new);
+        }
+        if limits.write_bps != self.limits.write_bps {
+            self.write = limits.write_bps.map(TokenBucket::new);
+        }
+        if limits.iops != self.limits.iops {
+            self.iops = limits.iops.map(TokenBucket::new);
+        }
+        self.limits = limits;
+    }
+
+    /// Whether any limit is configured.
+    pub fn enabled(&self) -> bool {
+        self.read.is_some() || self.write.is_some() || self.iops.is_some()
+    }
+
+    /// Account a read of `len` bytes and return how long the caller must
+    /// sleep before serving it.
+    pub fn read(&mut self, len: u64) -> Duration {
+        self.stats.bytes_read += len;
+        let wait = self.op_wait().max(self.read.as_mut().map_or(Duration::ZERO, |b| b.take(len)));
+        self.account(wait)
+    }
+
+    /// Account a write of `len` bytes and return how long the caller must
+    /// sleep before serving it.
+    pub fn write(&mut self, len: u64) -> Duration {
+        self.stats.bytes_written += len;
+        let wait = self.op_wait().max(self.write.as_mut().map_or(Duration::ZERO, |b| b.take(len)));
+        self.account(wait)
+    }
+
+    fn op_wait(&mut self) -> Duration {
+        self.stats.ops += 1;
+        self.iops.as_mut().map_or(Duration::ZERO, |b| b.take(1))
+    }
+
+    fn account(&mut self, wait: Duration) -> Duration {
+        if wait > Duration::ZERO {
+            self.stats.throttled_ops += 1;
+            self.stats.throttled_for += wait;
+        }
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tunables_override_the_command_line() {
+        let base = ThrottleLimits { read_bps: Some(100), write_bps: Some(200), iops: None };
+        let limits = base.apply("# comment\n\nmax_write_bps = none\nmax_iops=50\n").unwrap();
+        assert_eq!(limits, ThrottleLimits { read_bps: Some(100), write_bps: None, iops: Some(50) });
+        assert_eq!(base.changes(&limits), ["max_write_bps: 200 -> none", "max_iops: none -> 50"]);
+    }
+
+    #[test]
+    fn tunables_reject_other_settings() {
+        let err = ThrottleLimits::default().apply("max_iops = 5\nio_retries = 2").unwrap_err();
+        assert!(err.starts_with("line 2: unknown or non-reloadable setting `io_retries`"), "{}", err);
+        assert!(ThrottleLimits::default().apply("max_iops = fast").is_err());
+        assert!(ThrottleLimits::default().apply("max_iops").is_err());
+    }
+
+    #[test]
+    fn set_limits_applies_at_runtime() {
+        let mut throttle = Throttle::new(ThrottleLimits { write_bps: Some(1000), ..Default::default() });
+        assert_eq!(throttle.write(1000), Duration::ZERO);
+        assert!(throttle.write(1000) > Duration::from_millis(900));
+        throttle.set_limits(ThrottleLimits::default());
+        assert_eq!(throttle.write(1_000_000), Duration::ZERO);
+        assert!(!throttle.enabled());
+    }
+}
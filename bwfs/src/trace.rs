@@ -0,0 +1,155 @@
+//! Operation tracing for ImageFS.
+//!
+//! With `--trace-file PATH`, every mutating FUSE operation is appended to
+//! the trace as one JSON object per line:
+//!
+//! ```json
+//! {"op":"write","ino":5,"parent":0,"name_hash":0,"offset":0,"len":4096,"result":0,"ts_us":1718000000000000}
+//! ```
+//!
+//! Names are stored as a hash so traces can be shared without leaking
+//! file names. The hash is 64-bit FNV-1a of the name's bytes, which is
+//! fully specified, so traces from different builds can be compared.
+//! Records are handed to a dedicated writer thread through a bounded
+//! channel, so trace-file IO never blocks a FUSE handler; when the channel
+//! is full, or the writer has stopped, the record is dropped and counted
+//! instead.
+
+use serde::Serialize;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Records buffered between the handlers and the writer thread.
+const TRACE_QUEUE_DEPTH: usize = 4096;
+
+/// One traced operation.
+#[derive(Serialize)]
+pub struct TraceRecord {
+    pub op: &'static str,
+    pub ino: u64,
+    pub parent: u64,
+    pub name_hash: u64,
+    pub offset: i64,
+    pub len: u64,
+    /// 0 on success, otherwise the errno returned to the kernel.
+    pub result: i32,
+    /// Microseconds since the UNIX epoch.
+    pub ts_us: u64,
+}
+
+impl TraceRecord {
+    pub fn new(op: &'static str, ino: u64) -> Self {
+        let ts_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        Self { op, ino, parent: 0, name_hash: 0, offset: 0, len: 0, result: 0, ts_us }
+    }
+
+    pub fn entry(mut self, parent: u64, name: &OsStr) -> Self {
+        self.parent = parent;
+        self.name_hash = name_hash(name);
+        self
+    }
+
+    pub fn range(mut self, offset: i64, len: u64) -> Self {
+        self.offset = offset;
+        self.len = len;
+        self
+    }
+}
+
+/// Hash of a file name: 64-bit FNV-1a of its bytes, the same in every
+/// build and on every target.
+pub fn name_hash(name: &OsStr) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    name.as_bytes().iter().fold(OFFSET_BASIS, |h, &b| (h ^ b as u64).wrapping_mul(PRIME))
+}
+
+/// Handle to the background trace writer.
+pub struct Tracer {
+    tx: Option<SyncSender<TraceRecord>>,
+    writer: Option<JoinHandle<()>>,
+    dropped: AtomicU64,
+}
+
+impl Tracer {
+    /// Open (appending) the trace file and start the writer thread.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = sync_channel(TRACE_QUEUE_DEPTH);
+        let writer = std::thread::spawn(move || write_loop(rx, BufWriter::new(file)));
+        Ok(Self { tx: Some(tx), writer: Some(writer), dropped: AtomicU64::new(0) })
+    }
+
+    /// Queue a record without blocking; drops it if the queue is full or
+    /// the writer has stopped after a write error.
+    pub fn record(&self, rec: TraceRecord) {
+        if let Some(tx) = &self.tx {
+            if let Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) = tx.try_send(rec) {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of records dropped because the writer fell behind or stopped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Tracer {
+    /// Close the channel and wait for the writer to drain it.
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(w) = self.writer.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+/// Write records as JSON lines, flushing whenever the queue runs dry.
+fn write_loop(rx: Receiver<TraceRecord>, mut out: BufWriter<std::fs::File>) {
+    while let Ok(rec) = rx.recv() {
+        let mut next = Some(rec);
+        while let Some(rec) = next {
+            if serde_json::to_writer(&mut out, &rec).is_err() || out.write_all(b"\n").is_err() {
+                eprintln!("trace: write failed, tracing stopped");
+                return;
+            }
+            next = rx.try_recv().ok();
+        }
+        let _ = out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_hash_is_fnv1a() {
+        // Reference values of 64-bit FNV-1a.
+        assert_eq!(name_hash(OsStr::new("")), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(name_hash(OsStr::new("a")), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(name_hash(OsStr::new("foobar")), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn records_after_the_writer_stopped_are_counted() {
+        let (tx, rx) = sync_channel(1);
+        drop(rx);
+        let tracer = Tracer { tx: Some(tx), writer: None, dropped: AtomicU64::new(0) };
+        tracer.record(TraceRecord::new("write", 2));
+        tracer.record(TraceRecord::new("write", 2));
+        assert_eq!(tracer.dropped(), 2);
+    }
+}
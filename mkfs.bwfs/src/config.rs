@@ -12,6 +12,7 @@
 //! block_size = 125000
 //! total_blocks = 200
 //! inode_count = 1000
+//! meta_zone_percent = 10
 //!
 //! [network]
 //! listen_addr = 127.0.0.1
@@ -24,10 +25,14 @@
 //! fingerprint = BWFS_2024_V1
 //! ```
 //!
-//! All fields are mandatory except `network.peers`, which can be empty.
+//! All fields are mandatory except `network.peers`, which can be empty,
+//! and `filesystem.meta_zone_percent`, which defaults to 0 (no zoning).
 
 use configparser::ini::Ini;
 
+use crate::exit::{fail, Kind};
+use crate::fs_layout::SB_NAME_MAX;
+
 /// Holds all configuration parameters required by mkfs.bwfs.
 ///
 /// Each field corresponds directly to a key inside the `config.ini`,
@@ -40,8 +45,14 @@ use configparser::ini::Ini;
 /// - embed metadata (name, fingerprint) into the superblock
 /// - prepare networking metadata for distributed BWFS nodes
 /// - determine where to save the generated `.img` file(s)
+///
+/// Not every field is consumed by mkfs itself; the networking keys are
+/// loaded and validated here so the mounter can share this loader.
+#[allow(dead_code)]
 pub struct BwfsConfig {
-    /// Human-readable name of the filesystem.
+    /// Human-readable name of the filesystem, stored in the superblock.
+    /// At most `SB_NAME_MAX` bytes of ASCII letters, digits, `_`, `-`
+    /// and `.`, so it can also name a mount.
     pub name: String,
 
     /// Size of one block in bytes.
@@ -55,6 +66,10 @@ pub struct BwfsConfig {
     /// Number of inodes reserved in the inode table.
     pub inode_count: u64,
 
+    /// Share of the data blocks, in percent, reserved preferentially
+    /// for directory blocks. 0 disables zoning.
+    pub meta_zone_percent: u64,
+
     /// Address on which this node will listen for distributed BWFS commands.
     pub listen_addr: String,
 
@@ -87,52 +102,67 @@ pub struct BwfsConfig {
 /// - Validates that required fields exist.
 /// - Splits `network.peers` into a list.
 ///
-/// # Panics
+/// # Errors
 ///
-/// This function will `panic!()` with a descriptive message if:
+/// The tool exits with the `config` status (see `exit`) and a
+/// descriptive message if:
 ///
 /// - a required field is missing
-/// - a numeric field cannot be parsed
-/// - the configuration file cannot be loaded
+/// - a numeric field cannot be parsed or is out of range
+/// - `filesystem.name` is empty, too long or has other characters
+/// - the configuration file cannot be loaded (`not_found` if it does
+///   not exist)
 ///
 /// This is acceptable because `mkfs.bwfs` should fail fast on bad configuration.
 pub fn load_config(path: &str) -> BwfsConfig {
+    let context = [("config", path.to_string())];
+    let bad = |msg: String| -> ! { fail(Kind::Config, format!("{}: {}", path, msg), &context) };
+
+    if !std::path::Path::new(path).exists() {
+        fail(Kind::NotFound, format!("{}: config file does not exist", path), &context);
+    }
     let mut ini = Ini::new();
-    ini.load(path).expect("Could not load config.ini");
+    if let Err(e) = ini.load(path) {
+        bad(format!("could not load config: {}", e));
+    }
+
+    let text = |section: &str, key: &str| {
+        ini.get(section, key).unwrap_or_else(|| bad(format!("missing {}.{}", section, key)))
+    };
+    let number = |section: &str, key: &str| {
+        ini.getuint(section, key).unwrap_or_else(|_| bad(format!("invalid {}.{}", section, key)))
+    };
+    let required = |section: &str, key: &str| {
+        number(section, key).unwrap_or_else(|| bad(format!("missing {}.{}", section, key)))
+    };
 
     // -------------------------
     // [filesystem] section
     // -------------------------
-    let name = ini
-        .get("filesystem", "name")
-        .expect("missing filesystem.name");
-
-    let block_size = ini
-        .getuint("filesystem", "block_size")
-        .expect("missing filesystem.block_size")
-        .expect("invalid filesystem.block_size") as u64;
-
-    let total_blocks = ini
-        .getuint("filesystem", "total_blocks")
-        .expect("missing filesystem.total_blocks")
-        .expect("invalid filesystem.total_blocks") as u64;
-
-    let inode_count = ini
-        .getuint("filesystem", "inode_count")
-        .expect("missing filesystem.inode_count")
-        .expect("invalid filesystem.inode_count") as u64;
+    let name = text("filesystem", "name");
+    let name_ok = |c: char| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.');
+    if name.is_empty() || name.len() > SB_NAME_MAX || !name.chars().all(name_ok) {
+        bad(format!(
+            "filesystem.name must be 1 to {} ASCII letters, digits, '_', '-' or '.'",
+            SB_NAME_MAX
+        ));
+    }
+    let block_size = required("filesystem", "block_size");
+    let total_blocks = required("filesystem", "total_blocks");
+    let inode_count = required("filesystem", "inode_count");
+
+    // Optional: 0 keeps the whole block space as a single zone
+    let meta_zone_percent = number("filesystem", "meta_zone_percent").unwrap_or(0);
+    if meta_zone_percent > 100 {
+        bad("filesystem.meta_zone_percent must be at most 100".to_string());
+    }
 
     // -------------------------
     // [network] section
     // -------------------------
-    let listen_addr = ini
-        .get("network", "listen_addr")
-        .expect("missing network.listen_addr");
-
-    let listen_port = ini
-        .getuint("network", "listen_port")
-        .expect("missing network.listen_port")
-        .expect("invalid network.listen_port") as u16;
+    let listen_addr = text("network", "listen_addr");
+    let listen_port = u16::try_from(required("network", "listen_port"))
+        .unwrap_or_else(|_| bad("invalid network.listen_port".to_string()));
 
     // `peers` is optional: empty string → empty vector
     let peers_raw = ini.get("network", "peers").unwrap_or_default();
@@ -141,23 +171,16 @@ pub fn load_config(path: &str) -> BwfsConfig {
     // -------------------------
     // [storage] section
     // -------------------------
-    let data_dir = ini
-        .get("storage", "data_dir")
-        .expect("missing storage.data_dir");
-
-    let image_prefix = ini
-        .get("storage", "image_prefix")
-        .expect("missing storage.image_prefix");
-
-    let fingerprint = ini
-        .get("storage", "fingerprint")
-        .expect("missing storage.fingerprint");
+    let data_dir = text("storage", "data_dir");
+    let image_prefix = text("storage", "image_prefix");
+    let fingerprint = text("storage", "fingerprint");
 
     BwfsConfig {
         name,
         block_size,
         total_blocks,
         inode_count,
+        meta_zone_percent,
         listen_addr,
         listen_port,
         peers,
@@ -0,0 +1,180 @@
+//! Exit statuses and final error reporting shared by the BWFS tools.
+//!
+//! Scripts wrapping the tools tell failures apart by exit status:
+//!
+//! | status | kind          | meaning                                      |
+//! |--------|---------------|----------------------------------------------|
+//! | 0      |               | success                                      |
+//! | 1      | `io`          | any other IO failure                         |
+//! | 2      | `config`      | invalid config file or command line          |
+//! | 3      | `not_found`   | image, config or seal file does not exist    |
+//! | 4      | `corrupt`     | the image is inconsistent or fails its seal  |
+//! | 5      | `no_space`    | the output device is full                    |
+//! | 6      |               | reserved: `bwfs` refuses an existing mount   |
+//! | 7      | `unsupported` | image format newer than the tool             |
+//! | 130    |               | interrupted with Ctrl+C (see `progress`)     |
+//!
+//! clap exits with 2 on its own for a malformed command line, which is
+//! why `config` shares the status. Failures nobody anticipated still
+//! panic (status 101).
+//!
+//! With `--error-format json` the final error is written to stderr as a
+//! single JSON object instead of text:
+//!
+//! ```json
+//! {"code":4,"kind":"corrupt","message":"...","context":{"image":"a.img"}}
+//! ```
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How the final error is printed, from `--error-format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+/// Failure classes, each with its own exit status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Io,
+    Config,
+    NotFound,
+    Corrupt,
+    NoSpace,
+    Unsupported,
+}
+
+impl Kind {
+    /// Exit status for this kind.
+    pub fn code(self) -> i32 {
+        match self {
+            Kind::Io => 1,
+            Kind::Config => 2,
+            Kind::NotFound => 3,
+            Kind::Corrupt => 4,
+            Kind::NoSpace => 5,
+            Kind::Unsupported => 7,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Io => "io",
+            Kind::Config => "config",
+            Kind::NotFound => "not_found",
+            Kind::Corrupt => "corrupt",
+            Kind::NoSpace => "no_space",
+            Kind::Unsupported => "unsupported",
+        }
+    }
+
+    /// Kind of a failed IO call. A short read means a truncated image.
+    pub fn of(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Kind::NotFound,
+            io::ErrorKind::UnexpectedEof => Kind::Corrupt,
+            io::ErrorKind::StorageFull => Kind::NoSpace,
+            _ => Kind::Io,
+        }
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Select the format [`fail`] prints in; called once from `main`.
+pub fn set_format(format: ErrorFormat) {
+    JSON.store(format == ErrorFormat::Json, Ordering::SeqCst);
+}
+
+/// Report the final error and exit with the status of `kind`.
+///
+/// `context` holds machine-readable details such as paths or offsets;
+/// text output only shows `message`, which should already name them.
+pub fn fail(kind: Kind, message: impl std::fmt::Display, context: &[(&str, String)]) -> ! {
+    eprintln!("{}", render(kind, &message.to_string(), context, JSON.load(Ordering::SeqCst)));
+    std::process::exit(kind.code());
+}
+
+/// The line [`fail`] prints: `message` alone, or the JSON object.
+fn render(kind: Kind, message: &str, context: &[(&str, String)], json: bool) -> String {
+    if !json {
+        return message.to_string();
+    }
+    let context: Vec<String> = context.iter().map(|(k, v)| format!("{}:{}", json_string(k), json_string(v))).collect();
+    format!(
+        "{{\"code\":{},\"kind\":{},\"message\":{},\"context\":{{{}}}}}",
+        kind.code(),
+        json_string(kind.name()),
+        json_string(message),
+        context.join(",")
+    )
+}
+
+/// `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_kind_has_its_documented_status() {
+        let kinds = [
+            (Kind::Io, 1, "io"),
+            (Kind::Config, 2, "config"),
+            (Kind::NotFound, 3, "not_found"),
+            (Kind::Corrupt, 4, "corrupt"),
+            (Kind::NoSpace, 5, "no_space"),
+            (Kind::Unsupported, 7, "unsupported"),
+        ];
+        for (kind, code, name) in kinds {
+            assert_eq!((kind.code(), kind.name()), (code, name));
+        }
+    }
+
+    #[test]
+    fn io_errors_map_to_kinds() {
+        let kind = |k| Kind::of(&io::Error::from(k));
+        assert_eq!(kind(io::ErrorKind::NotFound), Kind::NotFound);
+        assert_eq!(kind(io::ErrorKind::UnexpectedEof), Kind::Corrupt);
+        assert_eq!(kind(io::ErrorKind::StorageFull), Kind::NoSpace);
+        assert_eq!(kind(io::ErrorKind::PermissionDenied), Kind::Io);
+    }
+
+    #[test]
+    fn errors_render_as_text_or_one_json_object() {
+        let context = [("image", "a.img".to_string()), ("offset", "4096".to_string())];
+        assert_eq!(render(Kind::Corrupt, "a.img: bad", &context, false), "a.img: bad");
+        assert_eq!(
+            render(Kind::Corrupt, "a.img: bad", &context, true),
+            r#"{"code":4,"kind":"corrupt","message":"a.img: bad","context":{"image":"a.img","offset":"4096"}}"#
+        );
+        assert_eq!(
+            render(Kind::Config, "no config", &[], true),
+            r#"{"code":2,"kind":"config","message":"no config","context":{}}"#
+        );
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a \"b\" \\ c\nd\te\u{1}"), r#""a \"b\" \\ c\nd\u0009e\u0001""#);
+        assert_eq!(json_string("é"), "\"é\"");
+    }
+}
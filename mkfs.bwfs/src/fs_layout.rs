@@ -4,10 +4,37 @@
 //! They are written directly to disk by `mkfs.bwfs` and later read by
 //! the mounter (`mount.bwfs`) and diagnostic tools (e.g. `bwfs-info`).
 //!
-//! All structs are annotated with `#[repr(C)]` so the compiler guarantees
-//! predictable field order and binary compatibility. This is essential
-//! in a filesystem: the layout must remain stable and independent of
-//! Rust compiler optimizations.
+//! The layout must not depend on the compiler or the target, so the
+//! structs are never copied to disk as memory. Each one implements
+//! [`OnDisk`], which encodes its fields one by one in declaration order,
+//! little-endian, with no implicit padding. An image therefore reads the
+//! same on 32- and 64-bit, little- and big-endian targets alike. The few
+//! padding bytes the layout has are spelled out as fields that are
+//! always zero.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::exit::{fail, Kind};
+
+/// On-disk layout version written by mkfs.
+///
+/// History:
+/// - 1: initial layout
+/// - 2: `Inode::crtime` appended
+/// - 3: `Inode::atime`, `mtime` and `ctime` appended
+/// - 4: `Inode::uid` and `gid` appended
+/// - 5: `Inode::indirect` appended
+/// - 6: `Inode::nlink` appended
+/// - 7: `Inode::double_indirect` appended
+///
+/// Fields are only ever appended to `Inode`, so an older inode is a
+/// prefix of the current one and reads back with the newer fields zero.
+pub const BWFS_VERSION: u32 = 7;
+
+/// Inode size of each layout version, starting at version 1.
+const INODE_SIZES: [u64; BWFS_VERSION as usize] = [112, 120, 144, 152, 160, 168, 176];
 
 /// Superblock: global header describing the entire filesystem.
 ///
@@ -22,13 +49,20 @@
 /// - `inode_count`:  Number of reserved inodes in the inode table.
 /// - `inode_table_start`: Offset *in bytes* where the inode table begins.
 /// - `data_area_start`:   Offset *in bytes* where block storage begins.
+/// - `uuid`: Random identifier generated by mkfs (all zeros if unset).
+/// - `meta_zone_blocks`: Leading data blocks reserved for metadata.
+/// - `feature_compat` / `feature_ro_compat` / `feature_incompat`:
+///   feature bitmasks, see [`check_features`].
+/// - `inode_size`: Size in bytes of one inode table entry.
+/// - `epoch`: Modification counter bumped by every tool that rewrites
+///   the image.
+/// - `name`: Filesystem name from the config, NUL-padded.
 ///
 /// Summary:
 ///   [0x0000] Superblock (fixed size)
 ///   [..]     Inode table (inode_count entries)
 ///   [..]     Data area (blocks)
-#[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Superblock {
     /// Filesystem identifier: always "BWFS".
     pub magic: [u8; 4],
@@ -50,6 +84,181 @@ pub struct Superblock {
 
     /// Byte offset to the start of the data block area.
     pub data_area_start: u64,
+
+    /// Filesystem UUID (RFC 4122 version 4).
+    /// Appended after the original fields; images formatted before it was
+    /// added read back as all zeros, meaning "no UUID".
+    pub uuid: [u8; 16],
+
+    /// Number of data blocks, starting at block 0, that the allocator
+    /// prefers for directory blocks; file data comes from the rest until
+    /// it is exhausted. 0 means no zoning, which is also what images
+    /// formatted before zones existed read back as.
+    pub meta_zone_blocks: u64,
+
+    /// Features an older reader can safely ignore.
+    pub feature_compat: u32,
+
+    /// Features an older reader may read but must not write.
+    pub feature_ro_compat: u32,
+
+    /// Features an older reader cannot interpret at all.
+    pub feature_incompat: u32,
+
+    /// Bytes per inode table entry, so readers can step through the table
+    /// without knowing the layout version. 0 in images formatted before
+    /// the field existed; use [`inode_size`] rather than reading it directly.
+    pub inode_size: u32,
+
+    /// Write generation: every tool that changes the image other than
+    /// through a mount bumps it, so a process that cached metadata from
+    /// the image can tell it was modified underneath. mkfs starts at 0,
+    /// which is also what images predating the field read back as.
+    pub epoch: u64,
+
+    /// Filesystem name from `filesystem.name`, NUL-padded. Appended like
+    /// `uuid`: images formatted before it read back as all zeros, meaning
+    /// "no name"; use [`fs_name`] rather than reading it directly.
+    pub name: [u8; SB_NAME_MAX],
+}
+
+/// Longest filesystem name the superblock holds, in bytes.
+pub const SB_NAME_MAX: usize = 32;
+
+/// `name` NUL-padded for [`Superblock::name`]; longer names are cut,
+/// which config validation rules out.
+pub fn encode_fs_name(name: &str) -> [u8; SB_NAME_MAX] {
+    let mut out = [0u8; SB_NAME_MAX];
+    let len = name.len().min(SB_NAME_MAX);
+    out[..len].copy_from_slice(&name.as_bytes()[..len]);
+    out
+}
+
+/// Filesystem name stored in `sb`, or `None` if it has none.
+pub fn fs_name(sb: &Superblock) -> Option<String> {
+    let len = sb.name.iter().position(|&b| b == 0).unwrap_or(SB_NAME_MAX);
+    (len > 0).then(|| String::from_utf8_lossy(&sb.name[..len]).into_owned())
+}
+
+impl OnDisk for Superblock {
+    const SIZE: usize = 128;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.magic);
+        out.extend_from_slice(&self.version.to_le_bytes());
+        for v in [self.block_size, self.total_blocks, self.inode_count, self.inode_table_start, self.data_area_start] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&self.uuid);
+        out.extend_from_slice(&self.meta_zone_blocks.to_le_bytes());
+        for v in [self.feature_compat, self.feature_ro_compat, self.feature_incompat, self.inode_size] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&self.epoch.to_le_bytes());
+        out.extend_from_slice(&self.name);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let mut d = Decoder::new(buf);
+        Self {
+            magic: d.bytes(),
+            version: d.u32(),
+            block_size: d.u64(),
+            total_blocks: d.u64(),
+            inode_count: d.u64(),
+            inode_table_start: d.u64(),
+            data_area_start: d.u64(),
+            uuid: d.bytes(),
+            meta_zone_blocks: d.u64(),
+            feature_compat: d.u32(),
+            feature_ro_compat: d.u32(),
+            feature_incompat: d.u32(),
+            inode_size: d.u32(),
+            epoch: d.u64(),
+            name: d.bytes(),
+        }
+    }
+}
+
+// ---------------------------------------------------------
+// Feature flags
+// ---------------------------------------------------------
+//
+// Same scheme as ext2: every on-disk feature claims a bit in one of three
+// masks, chosen by what happens when a reader that predates it opens the
+// image. Images formatted before the masks existed read back all zeros.
+
+/// Metadata zone in use (`meta_zone_blocks > 0`).
+pub const COMPAT_META_ZONES: u32 = 1 << 0;
+/// Journal region present.
+pub const COMPAT_JOURNAL: u32 = 1 << 1;
+
+/// Per-block checksums are stored.
+pub const RO_COMPAT_CHECKSUMS: u32 = 1 << 0;
+
+/// Inodes use indirect block pointers.
+pub const INCOMPAT_INDIRECT: u32 = 1 << 0;
+/// Extended attributes are stored in inode-owned blocks.
+pub const INCOMPAT_XATTR: u32 = 1 << 1;
+/// Data blocks may be compressed.
+pub const INCOMPAT_COMPRESSION: u32 = 1 << 2;
+/// Small files may keep their data packed into a shared block.
+pub const INCOMPAT_TAIL_PACK: u32 = 1 << 3;
+
+pub const COMPAT_NAMES: &[(u32, &str)] = &[(COMPAT_META_ZONES, "meta_zones"), (COMPAT_JOURNAL, "journal")];
+pub const RO_COMPAT_NAMES: &[(u32, &str)] = &[(RO_COMPAT_CHECKSUMS, "checksums")];
+pub const INCOMPAT_NAMES: &[(u32, &str)] = &[
+    (INCOMPAT_INDIRECT, "indirect"),
+    (INCOMPAT_XATTR, "xattr"),
+    (INCOMPAT_COMPRESSION, "compression"),
+    (INCOMPAT_TAIL_PACK, "tail_pack"),
+];
+
+/// Bits this build understands in each mask.
+///
+/// Every known feature is listed here even before its implementation
+/// lands, so the bit cannot be reused by something else.
+fn known_bits(names: &[(u32, &str)]) -> u32 {
+    names.iter().fold(0, |acc, (bit, _)| acc | bit)
+}
+
+/// How a reader built from this tree may open an image.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FeatureCheck {
+    /// Every bit that matters is understood.
+    ReadWrite,
+    /// Unknown ro_compat bits: reading is safe, writing is not.
+    ReadOnly(u32),
+    /// Unknown incompat bits: the image must not be opened.
+    Refuse(u32),
+}
+
+/// Decide how an image with `sb`'s feature masks may be opened.
+/// Unknown compat bits are ignored.
+pub fn check_features(sb: &Superblock) -> FeatureCheck {
+    let incompat = sb.feature_incompat & !known_bits(INCOMPAT_NAMES);
+    if incompat != 0 {
+        return FeatureCheck::Refuse(incompat);
+    }
+    let ro_compat = sb.feature_ro_compat & !known_bits(RO_COMPAT_NAMES);
+    if ro_compat != 0 {
+        return FeatureCheck::ReadOnly(ro_compat);
+    }
+    FeatureCheck::ReadWrite
+}
+
+/// Names of the set bits in `mask`; unknown bits print as hex.
+pub fn feature_names(mask: u32, names: &[(u32, &str)]) -> Vec<String> {
+    let mut out: Vec<String> = names
+        .iter()
+        .filter(|(bit, _)| mask & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let unknown = mask & !known_bits(names);
+    if unknown != 0 {
+        out.push(format!("unknown(0x{:x})", unknown));
+    }
+    out
 }
 
 /// Inode: metadata structure describing a file or directory.
@@ -59,18 +268,23 @@ pub struct Superblock {
 ///
 /// Fields:
 /// - `mode`: file type + permissions (UNIX-style bitmask).
-/// - `_pad`: alignment padding (ensures 64-bit alignment).
+/// - `_pad`, `_pad2`, `_pad3`: reserved, always zero.
 /// - `size`: file size in bytes.
 /// - `direct`: array of direct block pointers (logical block indices).
+/// - `crtime`: creation (birth) time, seconds since the UNIX epoch.
+/// - `atime` / `mtime` / `ctime`: access, modification and change times,
+///   seconds since the UNIX epoch.
+/// - `uid` / `gid`: owner and group.
+/// - `indirect` / `double_indirect`: blocks holding further block
+///   pointers, see [`read_block_map`].
+/// - `nlink`: number of directory entries naming the inode.
 ///
 /// This simplified inode structure omits:
-/// - timestamps
 /// - extended attributes
-/// - indirect/ double-indirect pointers
+/// - triple-indirect pointers
 ///
 /// It is sufficient for a teaching filesystem and small projects.
-#[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Inode {
     /// File type + permissions (UNIX layout).
     /// Examples:
@@ -78,8 +292,9 @@ pub struct Inode {
     /// - Regular file: 0o100000 | 0o644
     pub mode: u16,
 
-    /// Reserved padding for alignment.
+    /// Reserved, always zero; keeps `size` 8-byte aligned on disk.
     pub _pad: u16,
+    pub _pad2: u32,
 
     /// Logical file size in bytes.
     pub size: u64,
@@ -87,7 +302,41 @@ pub struct Inode {
     /// Direct pointers to data blocks.
     /// `direct[0]` is typically the first block of file data.
     /// Direct pointers simplify implementation by avoiding indirect blocks.
-    pub direct: [u64; 12],
+    pub direct: [u64; DIRECT_BLOCKS],
+
+    /// Creation time in seconds since the UNIX epoch.
+    /// Set once when the inode is allocated and never updated. Zero in
+    /// inodes from version 1.
+    pub crtime: u64,
+
+    /// Last access, modification and change times, in seconds since the
+    /// UNIX epoch. Zero in inodes upgraded from before version 3.
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+
+    /// Owner and group. Zero (root) in inodes from before version 4.
+    pub uid: u32,
+    pub gid: u32,
+
+    /// Single indirect block: `block_size / 8` little-endian u64
+    /// pointers to the blocks after `direct`. Only meaningful once the
+    /// size reaches past the direct blocks, on images with
+    /// `INCOMPAT_INDIRECT`; block 0 is a valid block, so zero does not
+    /// mean unused.
+    pub indirect: u64,
+
+    /// Directory entries referring to this inode, `.` and `..` included:
+    /// a file's hard links, or 2 plus the subdirectories of a directory.
+    /// The inode and its blocks are freed when it drops to zero. Zero
+    /// in inodes from before version 6; `bwfs_upgrade` recounts them.
+    pub nlink: u32,
+    pub _pad3: u32,
+
+    /// Double indirect block: pointers to further single indirect
+    /// blocks, for the blocks after those `indirect` reaches. Used like
+    /// `indirect`, from version 7 on.
+    pub double_indirect: u64,
 }
 
 impl Inode {
@@ -98,58 +347,444 @@ impl Inode {
         Self {
             mode: 0,
             _pad: 0,
+            _pad2: 0,
             size: 0,
-            direct: [0; 12],
+            direct: [0; DIRECT_BLOCKS],
+            crtime: 0,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+            uid: 0,
+            gid: 0,
+            indirect: 0,
+            nlink: 0,
+            _pad3: 0,
+            double_indirect: 0,
         }
     }
 }
 
-/// Convert any `Copy` struct into a raw byte vector.
-///
-/// This is needed because the filesystem image is written as a
-/// binary blob. We must serialize the structs exactly as they appear
-/// in memory.
+impl OnDisk for Inode {
+    const SIZE: usize = 176;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.mode.to_le_bytes());
+        out.extend_from_slice(&self._pad.to_le_bytes());
+        out.extend_from_slice(&self._pad2.to_le_bytes());
+        out.extend_from_slice(&self.size.to_le_bytes());
+        for p in self.direct {
+            out.extend_from_slice(&p.to_le_bytes());
+        }
+        for v in [self.crtime, self.atime, self.mtime, self.ctime] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&self.uid.to_le_bytes());
+        out.extend_from_slice(&self.gid.to_le_bytes());
+        out.extend_from_slice(&self.indirect.to_le_bytes());
+        out.extend_from_slice(&self.nlink.to_le_bytes());
+        out.extend_from_slice(&self._pad3.to_le_bytes());
+        out.extend_from_slice(&self.double_indirect.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let mut d = Decoder::new(buf);
+        Self {
+            mode: d.u16(),
+            _pad: d.u16(),
+            _pad2: d.u32(),
+            size: d.u64(),
+            direct: std::array::from_fn(|_| d.u64()),
+            crtime: d.u64(),
+            atime: d.u64(),
+            mtime: d.u64(),
+            ctime: d.u64(),
+            uid: d.u32(),
+            gid: d.u32(),
+            indirect: d.u64(),
+            nlink: d.u32(),
+            _pad3: d.u32(),
+            double_indirect: d.u64(),
+        }
+    }
+}
+
+const _: () = assert!(INODE_SIZES[BWFS_VERSION as usize - 1] == Inode::SIZE as u64);
+
+/// Inode size of layout `version`, if this tool knows it.
+pub fn layout_inode_size(version: u32) -> Option<u64> {
+    INODE_SIZES.get((version as usize).checked_sub(1)?).copied()
+}
+
+/// Size of one inode table entry in the image described by `sb`.
 ///
-/// # Safety
+/// Falls back to the size implied by the layout version when the
+/// superblock predates its `inode_size` field.
+pub fn inode_size(sb: &Superblock) -> u64 {
+    match (sb.inode_size, sb.version) {
+        (0, v) => layout_inode_size(v).unwrap_or(Inode::SIZE as u64),
+        (n, _) => n as u64,
+    }
+}
+
+/// Number of direct block pointers in an inode.
+pub const DIRECT_BLOCKS: usize = 12;
+
+/// Block pointers held by one indirect block.
+pub fn pointers_per_block(sb: &Superblock) -> u64 {
+    sb.block_size / 8
+}
+
+/// Largest file an image can represent: what the direct pointers reach,
+/// plus, with `INCOMPAT_INDIRECT`, the single indirect block's and from
+/// version 7 on the double indirect block's. Writers must fail with
+/// EFBIG past it.
+pub fn max_file_size(sb: &Superblock) -> u64 {
+    let ppb = pointers_per_block(sb);
+    let mut blocks = DIRECT_BLOCKS as u64;
+    if sb.feature_incompat & INCOMPAT_INDIRECT != 0 {
+        blocks += ppb;
+        if sb.version >= 7 {
+            blocks = blocks.saturating_add(ppb.saturating_mul(ppb));
+        }
+    }
+    blocks.saturating_mul(sb.block_size)
+}
+
+/// Blocks in use by an inode.
+pub struct BlockMap {
+    /// Data blocks in logical order.
+    pub data: Vec<u64>,
+    /// Blocks holding pointers rather than data: the single indirect
+    /// block, the double indirect block and the blocks it points to.
+    pub pointer_blocks: Vec<u64>,
+}
+
+/// Read the block map of `inode`.
 ///
-/// The function uses `std::ptr::copy_nonoverlapping` to copy the struct’s
-/// memory representation into a `Vec<u8>`. Because the struct is annotated
-/// with `#[repr(C)]`, its layout is stable and safe to copy byte-for-byte.
+/// Only the first `ceil(size / block_size)` pointers are live; the rest
+/// are zero padding and say nothing (block 0 is a valid block). Sizes
+/// past [`max_file_size`] are clamped to it. An inline symlink's
+/// pointers hold its target and reference no blocks. A pointer block
+/// outside the data area is an `InvalidData` error.
+pub fn read_block_map(file: &mut File, sb: &Superblock, inode: &Inode) -> std::io::Result<BlockMap> {
+    let mut map = BlockMap { data: Vec::new(), pointer_blocks: Vec::new() };
+    if is_inline_symlink(inode) {
+        return Ok(map);
+    }
+    let ppb = pointers_per_block(sb);
+    let live = inode.size.min(max_file_size(sb)).div_ceil(sb.block_size);
+    let direct = live.min(DIRECT_BLOCKS as u64) as usize;
+    map.data.extend_from_slice(&inode.direct[..direct]);
+
+    let mut rest = live - direct as u64;
+    if rest > 0 {
+        let n = rest.min(ppb);
+        map.data.extend(read_pointers(file, sb, inode.indirect, n)?);
+        map.pointer_blocks.push(inode.indirect);
+        rest -= n;
+    }
+    if rest > 0 {
+        let second = read_pointers(file, sb, inode.double_indirect, rest.div_ceil(ppb))?;
+        map.pointer_blocks.push(inode.double_indirect);
+        for block in second {
+            let n = rest.min(ppb);
+            map.data.extend(read_pointers(file, sb, block, n)?);
+            map.pointer_blocks.push(block);
+            rest -= n;
+        }
+    }
+    Ok(map)
+}
+
+/// The first `count` pointers stored in pointer block `block`.
+fn read_pointers(file: &mut File, sb: &Superblock, block: u64, count: u64) -> std::io::Result<Vec<u64>> {
+    if block >= sb.total_blocks {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("pointer block {} is past the data area", block),
+        ));
+    }
+    let mut buf = vec![0u8; count as usize * 8];
+    file.seek(SeekFrom::Start(block_offset(sb, block)?))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect())
+}
+
+/// Byte offset of data block `block` in the image; `InvalidData` if it
+/// does not fit in a `u64`.
+pub fn block_offset(sb: &Superblock, block: u64) -> std::io::Result<u64> {
+    block.checked_mul(sb.block_size).and_then(|off| off.checked_add(sb.data_area_start)).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("block {} lies past any image", block))
+    })
+}
+
+/// Read the superblock at the start of `file` and check that it can be
+/// interpreted at all.
 ///
-/// This function does **not** perform any endianness conversion.
-/// All fields are written in native little-endian format,
-/// matching how most CPUs represent integers.
+/// Fields appended to the superblock since an image was formatted read
+/// back as zero, because the superblock area is zero-filled up to the
+/// inode table. That only holds if the area is big enough for this
+/// tool's `Superblock`, so a smaller one is refused, as is an inode size
+/// that does not match the image's layout version. The geometry must
+/// also hold together: a non-zero block size that is a multiple of 8,
+/// an inode table that ends before the data area, and a data area whose
+/// end fits in a `u64`.
+pub fn read_superblock(file: &mut File) -> Result<Superblock, String> {
+    let sb: Superblock = try_read_struct(file, 0).map_err(|e| format!("cannot read superblock: {}", e))?;
+
+    if &sb.magic != b"BWFS" {
+        return Err(format!("not a BWFS image (magic {:?})", String::from_utf8_lossy(&sb.magic)));
+    }
+    if sb.inode_table_start < Superblock::SIZE as u64 {
+        return Err(format!(
+            "superblock area is {} bytes, this tool's superblock needs {}",
+            sb.inode_table_start,
+            Superblock::SIZE
+        ));
+    }
+    if let Some(expected) = layout_inode_size(sb.version).filter(|&n| n != inode_size(&sb)) {
+        return Err(format!(
+            "inode size {} does not match image format v{} ({} bytes)",
+            inode_size(&sb),
+            sb.version,
+            expected
+        ));
+    }
+    if sb.block_size == 0 || !sb.block_size.is_multiple_of(8) {
+        return Err(format!("block size {} is not a positive multiple of 8", sb.block_size));
+    }
+    if sb.data_area_start < sb.inode_table_start {
+        return Err(format!(
+            "data area at {} starts before the inode table at {}",
+            sb.data_area_start, sb.inode_table_start
+        ));
+    }
+    let table_end = sb.inode_count.checked_mul(inode_size(&sb)).and_then(|len| len.checked_add(sb.inode_table_start));
+    if table_end.is_none_or(|end| end > sb.data_area_start) {
+        return Err(format!(
+            "{} inodes of {} bytes from {} overrun the data area at {}",
+            sb.inode_count,
+            inode_size(&sb),
+            sb.inode_table_start,
+            sb.data_area_start
+        ));
+    }
+    if block_offset(&sb, sb.total_blocks).is_err() {
+        return Err(format!("{} blocks of {} bytes do not fit in an image", sb.total_blocks, sb.block_size));
+    }
+    Ok(sb)
+}
+
+/// Check that this tool knows the inode layout of the image.
+pub fn check_version(sb: &Superblock) -> Result<(), String> {
+    if sb.version == 0 || sb.version > BWFS_VERSION {
+        return Err(format!(
+            "image format v{}, this tool supports v1 to v{}",
+            sb.version, BWFS_VERSION
+        ));
+    }
+    Ok(())
+}
+
+/// Open the image at `path` for reading, exiting through [`fail`] if it
+/// cannot be opened.
+pub fn open_image_file(path: &str) -> File {
+    File::open(path).unwrap_or_else(|e| {
+        fail(Kind::of(&e), format!("{}: cannot open image: {}", path, e), &[("image", path.to_string())])
+    })
+}
+
+/// Open the image at `path` for reading and validate its superblock,
+/// exiting through [`fail`] if this tool cannot handle it.
+pub fn open_image(path: &str) -> (File, Superblock) {
+    let mut file = open_image_file(path);
+    let context = [("image", path.to_string())];
+    let sb = read_superblock(&mut file).unwrap_or_else(|e| fail(Kind::Corrupt, format!("{}: {}", path, e), &context));
+    check_version(&sb).unwrap_or_else(|e| fail(Kind::Unsupported, format!("{}: {}", path, e), &context));
+    (file, sb)
+}
+
+/// Read inode `index`, mapping older layouts into the current `Inode`.
 ///
-/// For a production filesystem you would consider portable encoding,
-/// but for a teaching OS/filesystem, native endian is acceptable.
-pub fn to_bytes<T: Copy>(v: &T) -> Vec<u8> {
-    let size = std::mem::size_of::<T>();
-    let mut buf = vec![0u8; size];
-    unsafe {
-        std::ptr::copy_nonoverlapping(v as *const T as *const u8, buf.as_mut_ptr(), size);
+/// `sb` must have passed [`check_version`].
+pub fn read_inode(file: &mut File, sb: &Superblock, index: u64) -> Inode {
+    try_read_inode(file, sb, index).expect("read failed")
+}
+
+/// Like [`read_inode`], but returns the error of a short or failed read.
+pub fn try_read_inode(file: &mut File, sb: &Superblock, index: u64) -> std::io::Result<Inode> {
+    let offset = sb.inode_table_start + index * inode_size(sb);
+    // Older layouts are prefixes of the current one; the fields they
+    // lack decode from the zero tail of the buffer.
+    let mut buf = vec![0u8; Inode::SIZE];
+    let len = (inode_size(sb) as usize).min(Inode::SIZE);
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf[..len])?;
+    Ok(Inode::decode(&buf))
+}
+
+/// Format a UUID in the usual 8-4-4-4-12 hex form.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// A structure with a fixed-size, little-endian on-disk encoding.
+pub trait OnDisk: Sized {
+    /// Encoded size in bytes.
+    const SIZE: usize;
+
+    /// Append the encoding of `self`, exactly `SIZE` bytes, to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decode the first `SIZE` bytes of `buf`. Every bit pattern decodes.
+    fn decode(buf: &[u8]) -> Self;
+}
+
+/// Little-endian field reader for [`OnDisk::decode`].
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> [u8; N] {
+        let out = self.buf[self.pos..self.pos + N].try_into().unwrap();
+        self.pos += N;
+        out
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.bytes())
     }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes())
+    }
+}
+
+/// Encode an on-disk struct, for writing it to the image.
+pub fn to_bytes<T: OnDisk>(v: &T) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(T::SIZE);
+    v.encode(&mut buf);
+    debug_assert_eq!(buf.len(), T::SIZE);
     buf
 }
 
+/// Read an on-disk struct from `file` at byte `offset`.
+///
+/// Inverse of [`to_bytes`].
+pub fn read_struct<T: OnDisk>(file: &mut File, offset: u64) -> T {
+    try_read_struct(file, offset).expect("read failed")
+}
+
+/// Like [`read_struct`], but hands a short or failed read back to the
+/// caller instead of panicking. For tools that look at damaged images.
+pub fn try_read_struct<T: OnDisk>(file: &mut File, offset: u64) -> std::io::Result<T> {
+    let mut buf = vec![0u8; T::SIZE];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(T::decode(&buf))
+}
+
 // ---------------------------------------------------------
 // Directory Entry structure
 // ---------------------------------------------------------
 
 pub const DIR_TYPE_FILE: u8 = 1;
 pub const DIR_TYPE_DIR: u8 = 2;
+pub const DIR_TYPE_SYMLINK: u8 = 3;
 
 pub const DIR_NAME_MAX: usize = 60;
 
+/// Directory entry type implied by an inode `mode`.
+///
+/// Images written by the old rename code can carry entries whose
+/// `file_type` disagrees with the inode; readers trust the inode mode.
+pub fn mode_dir_type(mode: u16) -> u8 {
+    match mode & 0o170000 {
+        0o040000 => DIR_TYPE_DIR,
+        0o120000 => DIR_TYPE_SYMLINK,
+        _ => DIR_TYPE_FILE,
+    }
+}
+
+/// Longest symlink target kept inline, in the bytes of `Inode::direct`.
+///
+/// A symlink inode (mode 0o120000) has `size` set to the target length.
+/// Targets up to this length take the place of the block pointers;
+/// longer ones are stored in the data block `direct[0]` points to.
+pub const SYMLINK_INLINE_MAX: usize = DIRECT_BLOCKS * 8;
+
+/// Whether `inode` is a symlink whose target is stored inline.
+pub fn is_inline_symlink(inode: &Inode) -> bool {
+    inode.mode & 0o170000 == 0o120000 && inode.size <= SYMLINK_INLINE_MAX as u64
+}
+
+/// Target of an inline symlink; `None` for any other inode.
+pub fn inline_symlink_target(inode: &Inode) -> Option<Vec<u8>> {
+    if !is_inline_symlink(inode) {
+        return None;
+    }
+    let mut bytes: Vec<u8> = inode.direct.iter().flat_map(|p| p.to_le_bytes()).collect();
+    bytes.truncate(inode.size as usize);
+    Some(bytes)
+}
+
 /// Directory entry mapping a filename to an inode number.
 /// Stored inside directory data blocks.
-#[repr(C)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct DirEntry {
     pub inode: u64,               // inode number
     pub name_len: u8,             // number of bytes used in `name`
-    pub file_type: u8,            // DIR_TYPE_FILE or DIR_TYPE_DIR
+    pub file_type: u8,            // DIR_TYPE_FILE, DIR_TYPE_DIR or DIR_TYPE_SYMLINK
     pub _pad: [u8; 6],            // alignment padding
     pub name: [u8; DIR_NAME_MAX], // UTF-8 bytes of filename
+    pub _pad_end: [u8; 4],        // explicit tail padding, always zero
+}
+
+impl OnDisk for DirEntry {
+    const SIZE: usize = 80;
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.inode.to_le_bytes());
+        out.push(self.name_len);
+        out.push(self.file_type);
+        out.extend_from_slice(&self._pad);
+        out.extend_from_slice(&self.name);
+        out.extend_from_slice(&self._pad_end);
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        let mut d = Decoder::new(buf);
+        let inode = d.u64();
+        let [name_len, file_type] = d.bytes();
+        Self { inode, name_len, file_type, _pad: d.bytes(), name: d.bytes(), _pad_end: d.bytes() }
+    }
+}
+
+/// Read the directory entries stored in data block `block`.
+pub fn read_dir_block(file: &mut File, sb: &Superblock, block: u64) -> std::io::Result<Vec<DirEntry>> {
+    let entry_size = DirEntry::SIZE as u64;
+    let offset = block_offset(sb, block)?;
+    (0..sb.block_size / entry_size)
+        .map(|slot| try_read_struct(file, offset + slot * entry_size))
+        .collect()
 }
 
 impl DirEntry {
@@ -160,6 +795,7 @@ impl DirEntry {
             file_type: 0,
             _pad: [0; 6],
             name: [0; DIR_NAME_MAX],
+            _pad_end: [0; 4],
         }
     }
 
@@ -176,3 +812,332 @@ impl DirEntry {
         e
     }
 }
+
+/// Number of directory entries naming each inode, `.` and `..` included,
+/// which is what `Inode::nlink` holds.
+pub fn count_links(file: &mut File, sb: &Superblock) -> std::io::Result<HashMap<u64, u32>> {
+    let mut links = HashMap::new();
+    for ino in 0..sb.inode_count {
+        let inode = read_inode(file, sb, ino);
+        if mode_dir_type(inode.mode) != DIR_TYPE_DIR || inode.mode == 0 {
+            continue;
+        }
+        for block in read_block_map(file, sb, &inode)?.data {
+            for e in read_dir_block(file, sb, block)? {
+                if e.name_len != 0 {
+                    *links.entry(e.inode).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    Ok(links)
+}
+
+/// Scratch images for the tests of every module.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// A path in the temporary directory, removed with everything under
+    /// it when the test ends.
+    pub struct TempPath(pub PathBuf);
+
+    impl TempPath {
+        pub fn new(what: &str) -> Self {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            let name = format!("bwfs-{}-{}-{}", what, std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+            Self(std::env::temp_dir().join(name))
+        }
+
+        pub fn as_str(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0).or_else(|_| std::fs::remove_dir_all(&self.0));
+        }
+    }
+
+    /// Superblock of a small layout `version` image: 8 blocks of 1024
+    /// bytes and 4 inodes.
+    pub fn superblock(version: u32) -> Superblock {
+        let inode_size = layout_inode_size(version).unwrap_or(Inode::SIZE as u64);
+        Superblock {
+            magic: *b"BWFS",
+            version,
+            block_size: 1024,
+            total_blocks: 8,
+            inode_count: 4,
+            inode_table_start: 4096,
+            data_area_start: 4096 + 4 * inode_size,
+            uuid: [0; 16],
+            meta_zone_blocks: 0,
+            feature_compat: 0,
+            feature_ro_compat: 0,
+            feature_incompat: if version >= 5 { INCOMPAT_INDIRECT } else { 0 },
+            inode_size: inode_size as u32,
+            epoch: 0,
+            name: [0; SB_NAME_MAX],
+        }
+    }
+
+    /// Write an image with `sb`'s geometry to `path`: `inodes` from slot
+    /// 0 on, cut to the image's inode size as older layouts were, and
+    /// each `(block, bytes)` at the start of its data block.
+    pub fn write_image(path: &TempPath, sb: &Superblock, inodes: &[Inode], blocks: &[(u64, &[u8])]) {
+        let mut image = vec![0u8; (sb.data_area_start + sb.total_blocks * sb.block_size) as usize];
+        image[..Superblock::SIZE].copy_from_slice(&to_bytes(sb));
+        let size = inode_size(sb) as usize;
+        for (i, inode) in inodes.iter().enumerate() {
+            let at = sb.inode_table_start as usize + i * size;
+            image[at..at + size].copy_from_slice(&to_bytes(inode)[..size]);
+        }
+        for &(block, bytes) in blocks {
+            let at = (sb.data_area_start + block * sb.block_size) as usize;
+            image[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+        File::create(&path.0).and_then(|mut f| f.write_all(&image)).unwrap();
+    }
+
+    /// A directory block holding `entries`, zero-padded to `block_size`.
+    pub fn dir_block(sb: &Superblock, entries: &[DirEntry]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = entries.iter().flat_map(to_bytes).collect();
+        bytes.resize(sb.block_size as usize, 0);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use std::io::Write;
+
+    /// `read_superblock` of a good image whose superblock was replaced
+    /// by `sb`.
+    fn reread(sb: &Superblock) -> Result<Superblock, String> {
+        let path = TempPath::new("layout");
+        write_image(&path, &superblock(BWFS_VERSION), &[], &[]);
+        let mut file = std::fs::OpenOptions::new().read(true).write(true).open(&path.0).unwrap();
+        file.write_all(&to_bytes(sb)).unwrap();
+        read_superblock(&mut file)
+    }
+
+    #[test]
+    fn broken_geometry_is_refused() {
+        assert!(reread(&superblock(BWFS_VERSION)).is_ok());
+        type Damage = fn(&mut Superblock);
+        let cases: [(Damage, &str); 6] = [
+            (|sb| sb.block_size = 0, "block size 0 is not"),
+            (|sb| sb.block_size = 1001, "block size 1001 is not"),
+            (|sb| sb.data_area_start = 1024, "starts before the inode table"),
+            (|sb| sb.inode_count = 5, "overrun the data area"),
+            (|sb| sb.inode_count = u64::MAX / 8, "overrun the data area"),
+            (|sb| sb.total_blocks = u64::MAX / 2, "do not fit in an image"),
+        ];
+        for (damage, expected) in cases {
+            let mut sb = superblock(BWFS_VERSION);
+            damage(&mut sb);
+            let err = reread(&sb).err().unwrap_or_default();
+            assert!(err.contains(expected), "expected {:?}, got {:?}", expected, err);
+        }
+    }
+
+    #[test]
+    fn block_offsets_do_not_overflow() {
+        let sb = superblock(BWFS_VERSION);
+        assert_eq!(block_offset(&sb, 2).unwrap(), sb.data_area_start + 2048);
+        assert_eq!(block_offset(&sb, u64::MAX).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encodings_have_their_size_and_round_trip() {
+        let mut sb = superblock(BWFS_VERSION);
+        (sb.uuid, sb.epoch, sb.name) = ([7; 16], 3, encode_fs_name("round"));
+        let mut inode = Inode::empty();
+        (inode.mode, inode.size, inode.direct[11], inode.nlink, inode.double_indirect) =
+            (0o100600, 5, u64::MAX, 1, 1 << 40);
+        let entry = DirEntry::new(3, "file", false);
+
+        assert_eq!(to_bytes(&sb).len(), Superblock::SIZE);
+        assert_eq!(to_bytes(&inode).len(), Inode::SIZE);
+        assert_eq!(to_bytes(&entry).len(), DirEntry::SIZE);
+        assert_eq!(Superblock::decode(&to_bytes(&sb)), sb);
+        assert_eq!(Inode::decode(&to_bytes(&inode)), inode);
+        assert_eq!(DirEntry::decode(&to_bytes(&entry)), entry);
+    }
+
+    /// `n`'s `len` low bytes, least significant first.
+    fn le(n: u64, len: usize) -> Vec<u8> {
+        n.to_le_bytes()[..len].to_vec()
+    }
+
+    #[test]
+    fn superblock_decodes_from_little_endian_bytes() {
+        let mut raw = b"BWFS".to_vec();
+        raw.extend([7, 0, 0, 0]);
+        raw.extend([0x00, 0x04, 0, 0, 0, 0, 0, 0]);
+        raw.extend([8, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend([4, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend([0x00, 0x10, 0, 0, 0, 0, 0, 0]);
+        raw.extend([0xc0, 0x12, 0, 0, 0, 0, 0, 0]);
+        raw.extend(1..=16u8);
+        raw.extend([1, 2, 3, 4, 5, 6, 7, 8]);
+        raw.extend([1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0xb0, 0, 0, 0]);
+        raw.extend([0xff, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend(b"test");
+        raw.resize(Superblock::SIZE, 0);
+
+        let sb = Superblock::decode(&raw);
+        assert_eq!((&sb.magic, sb.version, sb.block_size, sb.total_blocks), (b"BWFS", 7, 1024, 8));
+        assert_eq!((sb.inode_count, sb.inode_table_start, sb.data_area_start), (4, 4096, 4800));
+        assert_eq!(sb.uuid, std::array::from_fn(|i| i as u8 + 1));
+        assert_eq!(sb.meta_zone_blocks, 0x0807_0605_0403_0201);
+        assert_eq!((sb.feature_compat, sb.feature_ro_compat, sb.feature_incompat), (1, 0, 1));
+        assert_eq!((sb.inode_size, sb.epoch), (176, 255));
+        assert_eq!(fs_name(&sb).as_deref(), Some("test"));
+        assert_eq!(to_bytes(&sb), raw);
+
+        // A big-endian host copying the struct to disk would have
+        // written the version as 00 00 00 07; that is not version 7.
+        raw[4..8].copy_from_slice(&[0, 0, 0, 7]);
+        assert_eq!(Superblock::decode(&raw).version, 7 << 24);
+    }
+
+    #[test]
+    fn inode_and_dir_entry_decode_from_little_endian_bytes() {
+        let mut raw = vec![0xa4, 0x81, 0, 0, 0, 0, 0, 0];
+        raw.extend(le(0x1234, 8));
+        raw.extend(le(1, 8));
+        raw.extend([0; 80]);
+        raw.extend([8, 7, 6, 5, 4, 3, 2, 1]);
+        for t in [100, 200, 300, 400] {
+            raw.extend(le(t, 8));
+        }
+        raw.extend([0xe8, 0x03, 0, 0, 100, 0, 0, 0]);
+        raw.extend(le(20, 8));
+        raw.extend([2, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend(le(0x0100_0000_0000, 8));
+        assert_eq!(raw.len(), Inode::SIZE);
+
+        let inode = Inode::decode(&raw);
+        assert_eq!((inode.mode, inode.size, inode.direct[0], inode.direct[11]), (0o100644, 0x1234, 1, 0x0102_0304_0506_0708));
+        assert_eq!((inode.crtime, inode.atime, inode.mtime, inode.ctime), (100, 200, 300, 400));
+        assert_eq!((inode.uid, inode.gid, inode.indirect, inode.nlink), (1000, 100, 20, 2));
+        assert_eq!(inode.double_indirect, 1 << 40);
+        assert_eq!(to_bytes(&inode), raw);
+
+        let mut raw = le(9, 8);
+        raw.extend([5, DIR_TYPE_DIR, 0, 0, 0, 0, 0, 0]);
+        raw.extend(b"hello");
+        raw.resize(DirEntry::SIZE, 0);
+        let entry = DirEntry::decode(&raw);
+        assert_eq!(entry, DirEntry::new(9, "hello", true));
+        assert_eq!(to_bytes(&entry), raw);
+    }
+
+    #[test]
+    fn unknown_features_downgrade_or_refuse() {
+        let with = |compat, ro_compat, incompat| Superblock {
+            feature_compat: compat,
+            feature_ro_compat: ro_compat,
+            feature_incompat: incompat,
+            ..superblock(BWFS_VERSION)
+        };
+        let all = |names| known_bits(names);
+        assert_eq!(check_features(&with(0, 0, 0)), FeatureCheck::ReadWrite);
+        assert_eq!(
+            check_features(&with(all(COMPAT_NAMES), all(RO_COMPAT_NAMES), all(INCOMPAT_NAMES))),
+            FeatureCheck::ReadWrite
+        );
+        // Unknown compat bits are ignored.
+        assert_eq!(check_features(&with(1 << 31, 0, 0)), FeatureCheck::ReadWrite);
+        // Unknown ro_compat bits leave the image readable only.
+        assert_eq!(check_features(&with(0, RO_COMPAT_CHECKSUMS | 1 << 9, 0)), FeatureCheck::ReadOnly(1 << 9));
+        assert_eq!(check_features(&with(1 << 31, 1 << 9, INCOMPAT_INDIRECT)), FeatureCheck::ReadOnly(1 << 9));
+        // Unknown incompat bits refuse the image, whatever else is set.
+        assert_eq!(check_features(&with(0, 0, INCOMPAT_INDIRECT | 1 << 20)), FeatureCheck::Refuse(1 << 20));
+        assert_eq!(check_features(&with(1 << 31, 1 << 9, 1 << 20 | 1 << 21)), FeatureCheck::Refuse(3 << 20));
+    }
+
+    #[test]
+    fn feature_names_show_unknown_bits_in_hex() {
+        assert!(feature_names(0, INCOMPAT_NAMES).is_empty());
+        assert_eq!(feature_names(INCOMPAT_INDIRECT | INCOMPAT_TAIL_PACK, INCOMPAT_NAMES), ["indirect", "tail_pack"]);
+        assert_eq!(feature_names(COMPAT_JOURNAL | 0x30, COMPAT_NAMES), ["journal", "unknown(0x30)"]);
+        assert_eq!(feature_names(1 << 31, RO_COMPAT_NAMES), ["unknown(0x80000000)"]);
+    }
+
+    /// `inode` as layout `version` stores it: the fields appended by
+    /// later versions are zero.
+    fn as_of(version: u32, inode: &Inode) -> Inode {
+        let mut old = *inode;
+        if version < 2 {
+            old.crtime = 0;
+        }
+        if version < 3 {
+            (old.atime, old.mtime, old.ctime) = (0, 0, 0);
+        }
+        if version < 4 {
+            (old.uid, old.gid) = (0, 0);
+        }
+        if version < 5 {
+            old.indirect = 0;
+        }
+        if version < 6 {
+            old.nlink = 0;
+        }
+        if version < 7 {
+            old.double_indirect = 0;
+        }
+        old
+    }
+
+    #[test]
+    fn every_historical_layout_decodes() {
+        let mut inode = Inode::empty();
+        (inode.mode, inode.size, inode.direct[0], inode.direct[11]) = (0o100644, 1000, 1, 7);
+        (inode.crtime, inode.atime, inode.mtime, inode.ctime) = (10, 20, 30, 40);
+        (inode.uid, inode.gid, inode.indirect, inode.nlink, inode.double_indirect) = (1000, 100, 5, 1, 6);
+        let mut other = inode;
+        (other.mode, other.size, other.nlink) = (0o40755, 1024, 2);
+
+        for version in 1..=BWFS_VERSION {
+            // Superblocks from before `inode_size` existed store zero
+            // there; both forms must decode.
+            for stored_size in [layout_inode_size(version).unwrap() as u32, 0] {
+                let sb = Superblock { inode_size: stored_size, ..superblock(version) };
+                let path = TempPath::new("historical");
+                write_image(&path, &sb, &[inode, other], &[]);
+                let mut file = File::open(&path.0).unwrap();
+                let read = read_superblock(&mut file).unwrap();
+                assert_eq!(check_version(&read), Ok(()));
+                assert_eq!(inode_size(&read), layout_inode_size(version).unwrap());
+                assert_eq!(read_inode(&mut file, &read, 0), as_of(version, &inode), "v{}", version);
+                assert_eq!(read_inode(&mut file, &read, 1), as_of(version, &other), "v{}", version);
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_layouts_are_refused_clearly() {
+        for version in [0, BWFS_VERSION + 1] {
+            let sb = reread(&superblock(version)).unwrap();
+            assert_eq!(
+                check_version(&sb),
+                Err(format!("image format v{}, this tool supports v1 to v{}", version, BWFS_VERSION))
+            );
+        }
+        let sb = Superblock { inode_size: 160, ..superblock(BWFS_VERSION) };
+        assert_eq!(reread(&sb).unwrap_err(), "inode size 160 does not match image format v7 (176 bytes)");
+        let sb = Superblock { inode_table_start: 64, ..superblock(BWFS_VERSION) };
+        assert_eq!(reread(&sb).unwrap_err(), "superblock area is 64 bytes, this tool's superblock needs 128");
+        let sb = Superblock { magic: *b"EXT2", ..superblock(BWFS_VERSION) };
+        assert_eq!(reread(&sb).unwrap_err(), "not a BWFS image (magic \"EXT2\")");
+    }
+}
@@ -2,20 +2,72 @@
 //!
 //! Usage:
 //!     bwfs_info <image_file>
+//!     bwfs_info --blockmap <image_file>   # exits 4 on cross-linked blocks
+//!     bwfs_info --hex <image_file>        # superblock area as hex
+//!     bwfs_info --inode N <image_file>    # exits 4 if inode N is invalid
+//!     bwfs_info --dirblock B <image_file> # exits 4 on invalid entries
+//!
+//! Exit statuses follow the convention in `exit`.
 
+// Each tool uses its own subset of the failure kinds.
+#[allow(dead_code)]
+mod exit;
+// Shared with mkfs; the writer-side helpers are unused here.
+#[allow(dead_code)]
 mod fs_layout;
 mod info;
 
-use clap::Parser;
+use clap::{ArgGroup, Parser};
+use exit::{ErrorFormat, Kind};
 
 /// Simple inspection tool for BWFS images
 #[derive(Parser)]
+#[command(group(ArgGroup::new("view").args(["blockmap", "hex", "inode", "dirblock"])))]
 struct Cli {
     /// Path to the .img file
     image: String,
+
+    /// Print every inode's block map and report cross-linked blocks
+    #[arg(long)]
+    blockmap: bool,
+
+    /// Hex dump the superblock area alongside the decoded fields
+    #[arg(long)]
+    hex: bool,
+
+    /// Decode a single inode and check it against the superblock
+    #[arg(long, value_name = "N")]
+    inode: Option<u64>,
+
+    /// Decode a data block as directory entries
+    #[arg(long, value_name = "B")]
+    dirblock: Option<u64>,
+
+    /// Print the final error as text or as a JSON object
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
 }
 
 fn main() {
     let args = Cli::parse();
-    info::print_fs_info(&args.image);
+    exit::set_format(args.error_format);
+    let invalid = if args.blockmap {
+        info::print_blockmap(&args.image)
+    } else if args.hex {
+        info::print_hex(&args.image)
+    } else if let Some(n) = args.inode {
+        info::print_inode(&args.image, n)
+    } else if let Some(b) = args.dirblock {
+        info::print_dirblock(&args.image, b)
+    } else {
+        info::print_fs_info(&args.image);
+        false
+    };
+    if invalid {
+        exit::fail(
+            Kind::Corrupt,
+            format!("{}: invalid structures found", args.image),
+            &[("image", args.image.clone())],
+        );
+    }
 }
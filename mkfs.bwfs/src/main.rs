@@ -14,8 +14,12 @@
 //! logic is implemented in `mkfs.rs`.
 
 mod config;
+mod exit;
+// Shared with bwfs-info; the reader-side helpers are unused here.
+#[allow(dead_code)]
 mod fs_layout;
 mod mkfs;
+mod progress;
 
 use clap::Parser;
 
@@ -31,6 +35,12 @@ use clap::Parser;
 /// - `-c, --config <FILE>`: Path to the `config.ini` file containing
 ///   filesystem layout and storage parameters.
 ///
+/// Optional arguments:
+/// - `--reproducible`: Byte-identical output for identical configs.
+/// - `--device <PATH>`: Format a block device instead of creating an image.
+/// - `-q, --quiet`: No progress output.
+/// - `--error-format json`: Final error as a JSON object (see `exit`).
+///
 /// Example:
 ///
 /// ```bash
@@ -41,13 +51,39 @@ struct Cli {
     /// Path to the configuration file (`.ini`) that defines filesystem parameters.
     #[arg(short, long)]
     config: String,
+
+    /// Fix timestamps (SOURCE_DATE_EPOCH or 0) and derive the UUID from
+    /// the filesystem name, so identical configs give identical images.
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Do not print progress.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Format this block device (or existing file) instead of creating
+    /// `<data_dir>/<image_prefix>.img`. It must be large enough for the
+    /// configured layout and not in use.
+    #[arg(long, value_name = "PATH")]
+    device: Option<String>,
+
+    /// Print the final error as text or as a JSON object.
+    #[arg(long, value_enum, default_value_t = exit::ErrorFormat::Text)]
+    error_format: exit::ErrorFormat,
 }
 
 fn main() {
     // Parse command-line arguments (clap handles error messages automatically)
     let args = Cli::parse();
+    exit::set_format(args.error_format);
 
     // Delegate all filesystem creation logic to mkfs::run_mkfs
     // main.rs focused on CLI behavior.
-    mkfs::run_mkfs(&args.config);
+    let opts = mkfs::MkfsOptions {
+        reproducible: args.reproducible,
+        quiet: args.quiet,
+        device: args.device,
+    };
+    progress::install_interrupt_handler();
+    mkfs::run_mkfs(&args.config, &opts);
 }
@@ -11,22 +11,52 @@
 //!
 //! After this step, the filesystem image is a valid BWFS filesystem.
 //! It can be inspected using bwfs-info, and later mounted via FUSE.
+//!
+//! With `--device` the filesystem is written to an existing block device
+//! (or file) instead: it is opened exclusively, never created, truncated
+//! or removed, and must already be large enough for the layout. Data
+//! blocks other than the root directory keep whatever they held before.
 
-use std::fs::{create_dir_all, OpenOptions};
-use std::io::{Seek, SeekFrom, Write};
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
 
 use crate::config::load_config;
-use crate::fs_layout::{to_bytes, DirEntry, Inode, Superblock};
+use crate::exit::{fail, Kind};
+use crate::progress::{self, Progress, EXIT_INTERRUPTED};
+use crate::fs_layout::{
+    encode_fs_name, format_uuid, to_bytes, DirEntry, Inode, OnDisk, Superblock, BWFS_VERSION, COMPAT_META_ZONES,
+    INCOMPAT_INDIRECT,
+};
+
+/// Formatting switches taken from the command line.
+pub struct MkfsOptions {
+    /// Produce byte-identical images for identical configs: timestamps
+    /// come from `SOURCE_DATE_EPOCH` (or 0) and the UUID is derived from
+    /// the filesystem name.
+    pub reproducible: bool,
+
+    /// Suppress the progress line.
+    pub quiet: bool,
+
+    /// Format this block device (or existing file) instead of creating
+    /// `<data_dir>/<image_prefix>.img`.
+    pub device: Option<String>,
+}
 
 /// Main entry point for mkfs.bwfs
 ///
 /// # Parameters
 /// `config_path` — path to the INI configuration file.
+/// `opts` — formatting switches.
 ///
 /// This function *fails fast* when configuration or disk operations are invalid.
 /// For filesystem tools, this is acceptable and expected.
-pub fn run_mkfs(config_path: &str) {
+pub fn run_mkfs(config_path: &str, opts: &MkfsOptions) {
     // ---------------------------------------------------------
     // 1) Load configuration
     // ---------------------------------------------------------
@@ -35,16 +65,25 @@ pub fn run_mkfs(config_path: &str) {
     // ---------------------------------------------------------
     // 2) Ensure output directory exists
     // ---------------------------------------------------------
-    create_dir_all(&cfg.data_dir).expect("cannot create data_dir");
+    if opts.device.is_none() {
+        create_dir_all(&cfg.data_dir).unwrap_or_else(|e| {
+            fail(Kind::of(&e), format!("{}: cannot create data_dir: {}", cfg.data_dir, e), &[("path", cfg.data_dir.clone())])
+        });
+    }
 
     // Build final path: <data_dir>/<image_prefix>.img
-    let image_path = format!("{}/{}.img", cfg.data_dir, cfg.image_prefix);
+    let image_path = match &opts.device {
+        Some(device) => device.clone(),
+        None => format!("{}/{}.img", cfg.data_dir, cfg.image_prefix),
+    };
     let path = Path::new(&image_path);
+    // A device given with --device is not ours to remove on failure.
+    let remove = opts.device.is_none();
 
     // ---------------------------------------------------------
     // 3) Compute filesystem layout in bytes
     // ---------------------------------------------------------
-    let inode_size = std::mem::size_of::<Inode>() as u64;
+    let inode_size = Inode::SIZE as u64;
     let inode_table_size = cfg.inode_count * inode_size;
 
     // Superblock fixed at 4096 bytes (4 KiB alignment)
@@ -59,30 +98,47 @@ pub fn run_mkfs(config_path: &str) {
     // ---------------------------------------------------------
     // 4) Create or truncate the filesystem image
     // ---------------------------------------------------------
-    let mut file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(path)
-        .expect("cannot create image");
-
-    file.set_len(total_size).unwrap();
+    let mut file = if opts.device.is_some() {
+        open_device(&image_path, total_size)
+    } else {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap_or_else(|e| write_failed(&image_path, remove, e));
+        file.set_len(total_size).unwrap_or_else(|e| write_failed(&image_path, remove, e));
+        file
+    };
 
     // ---------------------------------------------------------
     // 5) Write Superblock at offset 0
     // ---------------------------------------------------------
+    let meta_zone_blocks = cfg.total_blocks * cfg.meta_zone_percent / 100;
+    let feature_compat = if meta_zone_blocks > 0 { COMPAT_META_ZONES } else { 0 };
+
     let sb = Superblock {
         magic: *b"BWFS",
-        version: 1,
+        version: BWFS_VERSION,
         block_size: cfg.block_size,
         total_blocks: cfg.total_blocks,
         inode_count: cfg.inode_count,
         inode_table_start,
         data_area_start,
+        uuid: if opts.reproducible { name_uuid(&cfg.name) } else { random_uuid() },
+        // The root directory block (block 0) falls inside the zone
+        meta_zone_blocks,
+        feature_compat,
+        feature_ro_compat: 0,
+        // The inode layout has the indirect pointer.
+        feature_incompat: INCOMPAT_INDIRECT,
+        inode_size: inode_size as u32,
+        epoch: 0,
+        name: encode_fs_name(&cfg.name),
     };
 
-    file.seek(SeekFrom::Start(0)).unwrap();
-    file.write_all(&to_bytes(&sb)).unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
+    file.write_all(&to_bytes(&sb)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
 
     // ---------------------------------------------------------
     // 6) Write empty inode table
@@ -90,10 +146,26 @@ pub fn run_mkfs(config_path: &str) {
     let empty_inode = Inode::empty();
     let inode_bytes = to_bytes(&empty_inode);
 
-    file.seek(SeekFrom::Start(inode_table_start)).unwrap();
-    for _ in 0..cfg.inode_count {
-        file.write_all(&inode_bytes).unwrap();
+    // The inode table is the bulk of the writing; Ctrl+C is honoured
+    // between inodes and removes the unfinished image.
+    let mut progress = Progress::new("Writing inode table", cfg.inode_count, opts.quiet);
+
+    file.seek(SeekFrom::Start(inode_table_start)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
+    for i in 0..cfg.inode_count {
+        if progress::interrupted() {
+            drop(file);
+            if remove {
+                let _ = std::fs::remove_file(path);
+                eprintln!("\nInterrupted; removed incomplete image {}", image_path);
+            } else {
+                eprintln!("\nInterrupted; {} holds an incomplete filesystem", image_path);
+            }
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        file.write_all(&inode_bytes).unwrap_or_else(|e| write_failed(&image_path, remove, e));
+        progress.set(i + 1);
     }
+    progress.finish();
 
     // ---------------------------------------------------------
     // 7) Create ROOT inode (inode 0)
@@ -104,16 +176,27 @@ pub fn run_mkfs(config_path: &str) {
     // - permissions (0o755)
     // - size = 1 full block
     // - direct[0] = block 0 (first block of data area)
+    // - crtime, atime, mtime, ctime = time of formatting
+    // - owned by root (uid 0, gid 0)
+    // - 2 links: its own "." and ".."
     //
-    let root_inode_offset = inode_table_start + 0 * inode_size;
+    // inode 0 is the first slot of the inode table
+    let root_inode_offset = inode_table_start;
 
     let mut root_inode = Inode::empty();
     root_inode.mode = 0o040755; // directory + rwxr-xr-x
     root_inode.size = cfg.block_size; // directory stored in one block
     root_inode.direct[0] = 0; // logical data block index 0
+    root_inode.crtime = format_time(opts);
+    root_inode.atime = root_inode.crtime;
+    root_inode.mtime = root_inode.crtime;
+    root_inode.ctime = root_inode.crtime;
+    root_inode.uid = 0;
+    root_inode.gid = 0;
+    root_inode.nlink = 2;
 
-    file.seek(SeekFrom::Start(root_inode_offset)).unwrap();
-    file.write_all(&to_bytes(&root_inode)).unwrap();
+    file.seek(SeekFrom::Start(root_inode_offset)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
+    file.write_all(&to_bytes(&root_inode)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
 
     // ---------------------------------------------------------
     // 8) Write ROOT directory block
@@ -129,21 +212,151 @@ pub fn run_mkfs(config_path: &str) {
     let dot = DirEntry::new(0, ".", true);
     let dotdot = DirEntry::new(0, "..", true);
 
-    let dir_entry_size = std::mem::size_of::<DirEntry>();
+    let dir_entry_size = DirEntry::SIZE;
 
-    file.seek(SeekFrom::Start(dir_block_offset)).unwrap();
-    file.write_all(&to_bytes(&dot)).unwrap();
-    file.write_all(&to_bytes(&dotdot)).unwrap();
+    file.seek(SeekFrom::Start(dir_block_offset)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
+    file.write_all(&to_bytes(&dot)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
+    file.write_all(&to_bytes(&dotdot)).unwrap_or_else(|e| write_failed(&image_path, remove, e));
 
     // Fill rest of directory block with zeros
     let used_bytes = 2 * dir_entry_size as u64;
     if used_bytes < cfg.block_size {
         let padding = vec![0u8; (cfg.block_size - used_bytes) as usize];
-        file.write_all(&padding).unwrap();
+        file.write_all(&padding).unwrap_or_else(|e| write_failed(&image_path, remove, e));
     }
 
     // ---------------------------------------------------------
     // Done
     // ---------------------------------------------------------
     println!("BWFS image created at {}", image_path);
+    println!("UUID: {}", format_uuid(&sb.uuid));
+}
+
+/// Open the `--device` target for formatting and check that the layout
+/// fits. O_EXCL makes the open fail with EBUSY while a block device is
+/// mounted or held exclusively by another program.
+fn open_device(device: &str, total_size: u64) -> File {
+    let context = [("image", device.to_string())];
+    let mut file = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_EXCL)
+        .open(device)
+        .unwrap_or_else(|e| fail(Kind::of(&e), format!("{}: cannot open device: {}", device, e), &context));
+    // Block devices report a length of 0; seeking to the end gives their size.
+    let size = file
+        .seek(SeekFrom::End(0))
+        .unwrap_or_else(|e| fail(Kind::of(&e), format!("{}: cannot get device size: {}", device, e), &context));
+    if size < total_size {
+        fail(
+            Kind::NoSpace,
+            format!("{}: device holds {} bytes, the layout needs {}", device, size, total_size),
+            &context,
+        );
+    }
+    file
+}
+
+/// Report a failed write and exit, removing the incomplete image if
+/// `remove`.
+fn write_failed(image_path: &str, remove: bool, e: io::Error) -> ! {
+    let mut removed = "";
+    if remove && std::fs::remove_file(image_path).is_ok() {
+        removed = ", removed it";
+    }
+    fail(
+        Kind::of(&e),
+        format!("{}: cannot write image{}: {}", image_path, removed, e),
+        &[("image", image_path.to_string())],
+    );
+}
+
+/// Timestamp recorded for everything mkfs creates.
+///
+/// `SOURCE_DATE_EPOCH` wins when set, as the reproducible-builds spec asks;
+/// otherwise reproducible images use 0 and normal ones the current time.
+fn format_time(opts: &MkfsOptions) -> u64 {
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        return epoch
+            .trim()
+            .parse()
+            .unwrap_or_else(|_| fail(Kind::Config, "invalid SOURCE_DATE_EPOCH", &[("SOURCE_DATE_EPOCH", epoch.clone())]));
+    }
+    if opts.reproducible {
+        return 0;
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Derive a stable UUID from the filesystem name (SHA-256, RFC 9562
+/// version 8 layout), so reproducible images of one config share it.
+fn name_uuid(name: &str) -> [u8; 16] {
+    let digest = Sha256::digest(name.as_bytes());
+    let mut uuid = [0u8; 16];
+    uuid.copy_from_slice(&digest[..16]);
+
+    uuid[6] = (uuid[6] & 0x0f) | 0x80; // version 8
+    uuid[8] = (uuid[8] & 0x3f) | 0x80; // RFC 4122 variant
+    uuid
+}
+
+/// Generate a random (version 4) UUID from the kernel's entropy pool.
+fn random_uuid() -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut uuid))
+        .expect("cannot read /dev/urandom");
+
+    uuid[6] = (uuid[6] & 0x0f) | 0x40; // version 4
+    uuid[8] = (uuid[8] & 0x3f) | 0x80; // RFC 4122 variant
+    uuid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_layout::testing::TempPath;
+
+    /// Write a config for a small filesystem into `dir` and return its path.
+    fn config(dir: &TempPath) -> String {
+        create_dir_all(&dir.0).unwrap();
+        let path = format!("{}/config.ini", dir.as_str());
+        let ini = format!(
+            "[filesystem]\nname = repro\nblock_size = 1000\ntotal_blocks = 40\ninode_count = 16\nmeta_zone_percent = 10\n\n\
+             [network]\nlisten_addr = 127.0.0.1\nlisten_port = 8080\npeers =\n\n\
+             [storage]\ndata_dir = {}\nimage_prefix = image\nfingerprint = BWFS_TEST\n",
+            dir.as_str()
+        );
+        std::fs::write(&path, ini).unwrap();
+        path
+    }
+
+    fn format(config_path: &str, reproducible: bool, device: Option<String>) {
+        run_mkfs(config_path, &MkfsOptions { reproducible, quiet: true, device });
+    }
+
+    #[test]
+    fn reproducible_images_are_byte_identical() {
+        let dir = TempPath::new("mkfs");
+        let config_path = config(&dir);
+        let image = format!("{}/image.img", dir.as_str());
+
+        format(&config_path, true, None);
+        let first = std::fs::read(&image).unwrap();
+        format(&config_path, true, None);
+        assert!(first == std::fs::read(&image).unwrap(), "second format differs");
+
+        // Formatting an existing file in place writes the same bytes.
+        let device = format!("{}/device", dir.as_str());
+        std::fs::write(&device, vec![0; first.len()]).unwrap();
+        format(&config_path, true, Some(device.clone()));
+        assert!(first == std::fs::read(&device).unwrap(), "device format differs");
+
+        // Without --reproducible every image gets its own UUID.
+        format(&config_path, false, None);
+        let uuid = |bytes: &[u8]| Superblock::decode(bytes).uuid;
+        assert_ne!(uuid(&std::fs::read(&image).unwrap()), uuid(&first));
+    }
 }
@@ -0,0 +1,69 @@
+//! Progress reporting and Ctrl+C handling shared by the BWFS tools.
+//!
+//! Long runs (formatting, upgrading or sealing a large image) print a
+//! percentage on stderr. The line is only drawn when stderr is a terminal
+//! and can be switched off entirely with `--quiet`, so scripts and logs
+//! stay clean.
+//!
+//! SIGINT does not kill the tool outright: it sets a flag that the work
+//! loop checks between units of work, so the tool can remove its
+//! half-written output and exit with [`EXIT_INTERRUPTED`].
+
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit status after a cooperative cancellation (128 + SIGINT).
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Route SIGINT to [`interrupted`] instead of terminating the process.
+pub fn install_interrupt_handler() {
+    ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst))
+        .expect("cannot install SIGINT handler");
+}
+
+/// Whether Ctrl+C was pressed since the handler was installed.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Percent-complete line on stderr.
+pub struct Progress {
+    label: &'static str,
+    total: u64,
+    last_pct: Option<u64>,
+    enabled: bool,
+}
+
+impl Progress {
+    pub fn new(label: &'static str, total: u64, quiet: bool) -> Self {
+        Self {
+            label,
+            total,
+            last_pct: None,
+            enabled: !quiet && std::io::stderr().is_terminal(),
+        }
+    }
+
+    /// Report `done` units out of the total; redraws only when the
+    /// percentage changes.
+    pub fn set(&mut self, done: u64) {
+        if !self.enabled {
+            return;
+        }
+        let pct = (done * 100).checked_div(self.total).unwrap_or(100);
+        if self.last_pct != Some(pct) {
+            self.last_pct = Some(pct);
+            eprint!("\r{}: {:3}%", self.label, pct);
+            let _ = std::io::stderr().flush();
+        }
+    }
+
+    /// End the progress line.
+    pub fn finish(&mut self) {
+        if self.enabled {
+            eprintln!("\r{}: done", self.label);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! bwfs-seal: tamper evidence for archived BWFS images.
+//!
+//! Sealing splits the image into fixed regions:
+//!   - leaf 0: the superblock area (`0 .. inode_table_start`)
+//!   - leaf 1: the inode table (`inode_table_start .. data_area_start`)
+//!   - leaf 2+: one per data block
+//!
+//! Each region is hashed with SHA-256 and the leaf hashes are combined
+//! pairwise into a Merkle root. The root and all leaves are written to a
+//! `<image>.seal` sidecar next to the image:
+//!
+//! ```text
+//! BWFS-SEAL 1
+//! root <hex>
+//! <leaf 0 hex>
+//! <leaf 1 hex>
+//! ...
+//! ```
+//!
+//! Verification recomputes every leaf and reports the first region that
+//! differs, so a damaged archive can be pinpointed without a full diff.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use sha2::{Digest, Sha256};
+
+use crate::exit::{fail, Kind};
+use crate::fs_layout::{block_offset, open_image, Superblock};
+use crate::progress::{self, Progress, EXIT_INTERRUPTED};
+
+type Hash = [u8; 32];
+
+const SEAL_HEADER: &str = "BWFS-SEAL 1";
+
+/// Byte ranges `(start, len)` covered by each leaf, in leaf order.
+fn leaf_ranges(sb: &Superblock) -> io::Result<Vec<(u64, u64)>> {
+    let table_len = sb.data_area_start.checked_sub(sb.inode_table_start).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "data area starts before the inode table")
+    })?;
+    let mut ranges = vec![(0, sb.inode_table_start), (sb.inode_table_start, table_len)];
+    for b in 0..sb.total_blocks {
+        ranges.push((block_offset(sb, b)?, sb.block_size));
+    }
+    Ok(ranges)
+}
+
+fn hash_range(file: &mut File, start: u64, len: u64) -> Hash {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut left = len;
+
+    file.seek(SeekFrom::Start(start)).expect("seek failed");
+    while left > 0 {
+        let n = left.min(buf.len() as u64) as usize;
+        file.read_exact(&mut buf[..n]).expect("read failed");
+        hasher.update(&buf[..n]);
+        left -= n as u64;
+    }
+    hasher.finalize().into()
+}
+
+/// Fold leaf hashes pairwise up to a single root.
+/// An odd node at the end of a level is carried up unchanged.
+fn merkle_root(leaves: &[Hash]) -> Hash {
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [a, b] => {
+                    let mut h = Sha256::new();
+                    h.update(a);
+                    h.update(b);
+                    h.finalize().into()
+                }
+                [a] => *a,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level.first().copied().unwrap_or([0; 32])
+}
+
+fn compute_leaves(path: &str, quiet: bool) -> Vec<Hash> {
+    let (mut file, sb) = open_image(path);
+
+    let ranges = leaf_ranges(&sb)
+        .unwrap_or_else(|e| fail(Kind::Corrupt, format!("{}: {}", path, e), &[("image", path.to_string())]));
+    let mut progress = Progress::new("Hashing", ranges.len() as u64, quiet);
+    let mut leaves = Vec::with_capacity(ranges.len());
+    for (start, len) in ranges {
+        // Nothing has been written yet, so there is nothing to clean up
+        if progress::interrupted() {
+            eprintln!("\nInterrupted");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        leaves.push(hash_range(&mut file, start, len));
+        progress.set(leaves.len() as u64);
+    }
+    progress.finish();
+    leaves
+}
+
+fn to_hex(h: &Hash) -> String {
+    h.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn seal_path(image: &str) -> String {
+    format!("{}.seal", image)
+}
+
+/// Hash the image and write the `<image>.seal` sidecar.
+pub fn seal_image(image: &str, quiet: bool) {
+    let leaves = compute_leaves(image, quiet);
+    let root = merkle_root(&leaves);
+
+    let mut out = format!("{}\nroot {}\n", SEAL_HEADER, to_hex(&root));
+    for leaf in &leaves {
+        out.push_str(&to_hex(leaf));
+        out.push('\n');
+    }
+    let path = seal_path(image);
+    std::fs::write(&path, out).unwrap_or_else(|e| {
+        fail(Kind::of(&e), format!("{}: cannot write seal file: {}", path, e), &[("seal", path.clone())])
+    });
+
+    println!("Sealed {} ({} regions)", image, leaves.len());
+    println!("Root hash: {}", to_hex(&root));
+}
+
+/// Compare the image against its sidecar.
+///
+/// Returns `true` when the image matches; otherwise prints the first
+/// divergent region and returns `false`.
+pub fn verify_image(image: &str, quiet: bool) -> bool {
+    let path = seal_path(image);
+    let context = [("seal", path.clone())];
+    let seal = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| fail(Kind::of(&e), format!("{}: cannot read seal file: {}", path, e), &context));
+    let mut lines = seal.lines();
+    if lines.next() != Some(SEAL_HEADER) {
+        fail(Kind::Corrupt, format!("{}: unrecognised seal file", path), &context);
+    }
+    let root = lines
+        .next()
+        .and_then(|l| l.strip_prefix("root "))
+        .unwrap_or_else(|| fail(Kind::Corrupt, format!("{}: seal file has no root hash", path), &context));
+    let sealed: Vec<&str> = lines.collect();
+
+    let leaves = compute_leaves(image, quiet);
+    if to_hex(&merkle_root(&leaves)) == root {
+        println!("OK: {} matches its seal", image);
+        return true;
+    }
+
+    if leaves.len() != sealed.len() {
+        println!("MISMATCH: image has {} regions, seal has {}", leaves.len(), sealed.len());
+        return false;
+    }
+    if let Some(idx) = leaves.iter().zip(&sealed).position(|(l, s)| to_hex(l) != *s) {
+        let region = match idx {
+            0 => "superblock".to_string(),
+            1 => "inode table".to_string(),
+            n => format!("data block {}", n - 2),
+        };
+        println!("MISMATCH: first divergent region is the {}", region);
+    } else {
+        println!("MISMATCH: leaves match but the root hash differs (corrupt seal file?)");
+    }
+    false
+}
@@ -0,0 +1,56 @@
+//! CLI entry point for `bwfs-seal`
+//!
+//! Usage:
+//!     bwfs_seal <image_file>            # write <image_file>.seal
+//!     bwfs_seal --verify <image_file>   # check the image against it
+//!
+//! `--quiet` suppresses the progress line. A failed verification exits
+//! with status 4; see `exit` for the others.
+
+// Each tool uses its own subset of the failure kinds.
+#[allow(dead_code)]
+mod exit;
+// Shared with mkfs; the writer-side helpers are unused here.
+#[allow(dead_code)]
+mod fs_layout;
+mod progress;
+mod seal;
+
+use clap::Parser;
+use exit::{ErrorFormat, Kind};
+
+/// Seal a BWFS image or verify it against an existing seal
+#[derive(Parser)]
+struct Cli {
+    /// Path to the .img file
+    image: String,
+
+    /// Verify instead of sealing; exits non-zero on mismatch
+    #[arg(long)]
+    verify: bool,
+
+    /// Do not print progress.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print the final error as text or as a JSON object
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+}
+
+fn main() {
+    let args = Cli::parse();
+    exit::set_format(args.error_format);
+    progress::install_interrupt_handler();
+    if args.verify {
+        if !seal::verify_image(&args.image, args.quiet) {
+            exit::fail(
+                Kind::Corrupt,
+                format!("{}: does not match its seal", args.image),
+                &[("image", args.image.clone())],
+            );
+        }
+    } else {
+        seal::seal_image(&args.image, args.quiet);
+    }
+}
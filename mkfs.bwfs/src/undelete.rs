@@ -0,0 +1,137 @@
+//! bwfs-undelete: recover regular files whose directory entry is gone.
+//!
+//! BWFS has no block bitmap. An inode is in use while a directory entry
+//! names it, so a deleted file is an inode that no entry names but that
+//! still has a size and a block map. Its blocks stay intact until another
+//! file is given them. A candidate is therefore an unlinked regular file
+//! (or an inode whose mode was cleared) with a size, with a block map that
+//! reads back inside the data area, and with no block that a linked inode
+//! uses.
+//!
+//! Without `--inode` the candidates are listed. With `--inode N --out FILE`
+//! the first `size` bytes of N's data blocks are copied to FILE, which
+//! must not exist yet. The image is only read.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::exit::{fail, Kind};
+use crate::fs_layout::{
+    block_offset, count_links, mode_dir_type, open_image, read_block_map, read_inode, BlockMap, Inode, Superblock,
+    DIR_TYPE_FILE,
+};
+
+/// Why an unlinked inode cannot be recovered, or `None` if it can.
+fn unrecoverable(
+    file: &mut File,
+    sb: &Superblock,
+    inode: &Inode,
+    owners: &HashMap<u64, u64>,
+) -> io::Result<Option<String>> {
+    if mode_dir_type(inode.mode) != DIR_TYPE_FILE {
+        return Ok(Some("not a regular file".to_string()));
+    }
+    if inode.size == 0 {
+        return Ok(Some("empty".to_string()));
+    }
+    let map = match read_block_map(file, sb, inode) {
+        Ok(map) => map,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(Some(e.to_string())),
+        Err(e) => return Err(e),
+    };
+    if let Some(&block) = map.data.iter().find(|&&b| b >= sb.total_blocks) {
+        return Ok(Some(format!("block {} is past the data area", block)));
+    }
+    for block in map.data.iter().chain(&map.pointer_blocks) {
+        if let Some(owner) = owners.get(block) {
+            return Ok(Some(format!("block {} now belongs to inode {}", block, owner)));
+        }
+    }
+    Ok(None)
+}
+
+/// Linked inodes by the blocks they use, data and pointer blocks alike.
+fn block_owners(file: &mut File, sb: &Superblock, links: &HashMap<u64, u32>) -> io::Result<HashMap<u64, u64>> {
+    let mut owners = HashMap::new();
+    for &ino in links.keys().filter(|&&ino| ino < sb.inode_count) {
+        let inode = read_inode(file, sb, ino);
+        // A damaged map of a live file claims nothing readable.
+        let Ok(BlockMap { data, pointer_blocks }) = read_block_map(file, sb, &inode) else { continue };
+        for block in data.into_iter().chain(pointer_blocks) {
+            owners.insert(block, ino);
+        }
+    }
+    Ok(owners)
+}
+
+fn io_fail(path: &str, e: io::Error) -> ! {
+    fail(Kind::of(&e), format!("{}: {}", path, e), &[("image", path.to_string())]);
+}
+
+/// List the inodes of the image at `path` that can be recovered.
+pub fn list_candidates(path: &str) {
+    let (mut file, sb) = open_image(path);
+    let links = count_links(&mut file, &sb).unwrap_or_else(|e| io_fail(path, e));
+    let owners = block_owners(&mut file, &sb, &links).unwrap_or_else(|e| io_fail(path, e));
+
+    let mut found = 0;
+    for ino in (0..sb.inode_count).filter(|ino| !links.contains_key(ino)) {
+        let inode = read_inode(&mut file, &sb, ino);
+        if inode.size == 0 {
+            continue;
+        }
+        if unrecoverable(&mut file, &sb, &inode, &owners).unwrap_or_else(|e| io_fail(path, e)).is_none() {
+            println!(
+                "inode {:>5}: {} bytes, mode 0o{:o}, uid {}, mtime {}",
+                ino, inode.size, inode.mode, inode.uid, inode.mtime
+            );
+            found += 1;
+        }
+    }
+    println!("{} recoverable inodes", found);
+}
+
+/// Copy the data of unlinked inode `ino` of the image at `path` to `out`.
+pub fn recover(path: &str, ino: u64, out: &str) {
+    let (mut file, sb) = open_image(path);
+    let context = [("image", path.to_string()), ("inode", ino.to_string())];
+    if ino >= sb.inode_count {
+        let message = format!("{}: inode {} does not exist; the image has {}", path, ino, sb.inode_count);
+        fail(Kind::Config, message, &context);
+    }
+    let links = count_links(&mut file, &sb).unwrap_or_else(|e| io_fail(path, e));
+    if links.contains_key(&ino) {
+        fail(Kind::Config, format!("{}: inode {} is still linked, nothing to undelete", path, ino), &context);
+    }
+    let owners = block_owners(&mut file, &sb, &links).unwrap_or_else(|e| io_fail(path, e));
+    let inode = read_inode(&mut file, &sb, ino);
+    if let Some(why) = unrecoverable(&mut file, &sb, &inode, &owners).unwrap_or_else(|e| io_fail(path, e)) {
+        fail(Kind::Corrupt, format!("{}: inode {} cannot be recovered: {}", path, ino, why), &context);
+    }
+
+    let mut dst = OpenOptions::new().write(true).create_new(true).open(out).unwrap_or_else(|e| {
+        fail(Kind::of(&e), format!("{}: cannot create: {}", out, e), &[("out", out.to_string())])
+    });
+    let data = read_block_map(&mut file, &sb, &inode).unwrap_or_else(|e| io_fail(path, e)).data;
+    let res = copy_blocks(&mut file, &sb, &data, inode.size, &mut dst).and_then(|()| dst.sync_all());
+    if let Err(e) = res {
+        let _ = std::fs::remove_file(out);
+        fail(Kind::of(&e), format!("{}: recovery of inode {} failed: {}", out, ino, e), &context);
+    }
+    println!("inode {}: {} bytes written to {}", ino, inode.size, out);
+}
+
+/// Write the first `size` bytes held by `blocks` to `dst`.
+fn copy_blocks(file: &mut File, sb: &Superblock, blocks: &[u64], size: u64, dst: &mut File) -> io::Result<()> {
+    let mut buf = vec![0u8; sb.block_size as usize];
+    let mut left = size;
+    for &block in blocks {
+        let n = left.min(sb.block_size) as usize;
+        file.seek(SeekFrom::Start(block_offset(sb, block)?))?;
+        file.read_exact(&mut buf[..n])?;
+        dst.write_all(&buf[..n])?;
+        left -= n as u64;
+    }
+    Ok(())
+}
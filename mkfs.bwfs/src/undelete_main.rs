@@ -0,0 +1,45 @@
+//! CLI entry point for `bwfs-undelete`
+//!
+//! Usage:
+//!     bwfs_undelete --image <image_file>                          # list candidates
+//!     bwfs_undelete --image <image_file> --inode <N> --out <file> # recover one
+
+// Each tool uses its own subset of the failure kinds.
+#[allow(dead_code)]
+mod exit;
+// Shared with mkfs; only the layout readers are used here.
+#[allow(dead_code)]
+mod fs_layout;
+mod undelete;
+
+use clap::Parser;
+use exit::ErrorFormat;
+
+/// List or recover deleted files of a BWFS image
+#[derive(Parser)]
+struct Cli {
+    /// Path to the .img file
+    #[arg(long)]
+    image: String,
+
+    /// Inode to recover; without it the recoverable inodes are listed
+    #[arg(long, requires = "out")]
+    inode: Option<u64>,
+
+    /// File to write the recovered data to; must not exist yet
+    #[arg(long, requires = "inode")]
+    out: Option<String>,
+
+    /// Print the final error as text or as a JSON object
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+}
+
+fn main() {
+    let args = Cli::parse();
+    exit::set_format(args.error_format);
+    match (args.inode, &args.out) {
+        (Some(ino), Some(out)) => undelete::recover(&args.image, ino, out),
+        _ => undelete::list_candidates(&args.image),
+    }
+}
@@ -0,0 +1,214 @@
+//! bwfs-upgrade: rewrite an image to the newest on-disk layout.
+//!
+//! A version 1 image has 112-byte inodes; version 2 adds `crtime`,
+//! version 3 the access, modification and change times, version 4 the
+//! owner and group, version 5 the indirect block pointer, version 6 the
+//! link count and version 7 the double indirect pointer. Each grows every
+//! inode and therefore moves the data area. Files from before version 4
+//! end up owned by root, upgraded images can hold files past the direct
+//! blocks, and link counts are recounted from the directory entries.
+//! Upgrading:
+//!   1. reads the old superblock and inode table, and counts the
+//!      directory entries naming each inode
+//!   2. writes a new image next to the old one (`<image>.upgrading`)
+//!      with the converted inode table and the data area copied over
+//!   3. syncs it and renames it over the original
+//!
+//! The rename is the commit point: a crash at any earlier step leaves the
+//! original image untouched, and the partial file can be deleted. Ctrl+C
+//! before the rename deletes it automatically.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileTypeExt;
+
+use crate::exit::{fail, Kind};
+use crate::progress::{self, Progress, EXIT_INTERRUPTED};
+use crate::fs_layout::{
+    count_links, inode_size, open_image, read_inode, to_bytes, Inode, OnDisk, Superblock, BWFS_VERSION,
+    INCOMPAT_INDIRECT,
+};
+
+/// Upgrade `path` in place to `BWFS_VERSION`.
+pub fn upgrade_image(path: &str, quiet: bool) {
+    // The upgrade renames a new image over the old one, which would
+    // replace a device node with a regular file.
+    if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_block_device()) {
+        fail(
+            Kind::Config,
+            format!(
+                "{}: is a block device and cannot be upgraded in place; copy it to an image file, upgrade that and write it back",
+                path
+            ),
+            &[("image", path.to_string())],
+        );
+    }
+
+    let (mut old, sb) = open_image(path);
+
+    if sb.version == BWFS_VERSION {
+        println!("{} is already at version {}", path, BWFS_VERSION);
+        return;
+    }
+
+    let new_inode_size = Inode::SIZE as u64;
+    let data_area_start = sb.inode_table_start + sb.inode_count * new_inode_size;
+    let data_len = sb.total_blocks * sb.block_size;
+
+    // Fields the old superblock lacks already read back as zero.
+    let new_sb = Superblock {
+        version: BWFS_VERSION,
+        data_area_start,
+        inode_size: new_inode_size as u32,
+        epoch: sb.epoch + 1,
+        feature_incompat: sb.feature_incompat | INCOMPAT_INDIRECT,
+        ..sb
+    };
+
+    let tmp_path = format!("{}.upgrading", path);
+    let mut new = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)
+        .unwrap_or_else(|e| failed(&tmp_path, path, e));
+    new.set_len(data_area_start + data_len).unwrap_or_else(|e| failed(&tmp_path, path, e));
+
+    // Superblock area: keep any bytes past the struct, then the new header
+    copy_range(&mut old, &mut new, 0, 0, sb.inode_table_start).unwrap_or_else(|e| failed(&tmp_path, path, e));
+    new.seek(SeekFrom::Start(0)).unwrap_or_else(|e| failed(&tmp_path, path, e));
+    new.write_all(&to_bytes(&new_sb)).unwrap_or_else(|e| failed(&tmp_path, path, e));
+
+    let links = match sb.version {
+        6.. => HashMap::new(),
+        _ => count_links(&mut old, &sb).unwrap_or_else(|e| failed(&tmp_path, path, e)),
+    };
+
+    let mut progress = Progress::new("Converting inodes", sb.inode_count, quiet);
+    new.seek(SeekFrom::Start(sb.inode_table_start)).unwrap_or_else(|e| failed(&tmp_path, path, e));
+    for i in 0..sb.inode_count {
+        if progress::interrupted() {
+            abort(&tmp_path, path);
+        }
+        let mut inode = read_inode(&mut old, &sb, i);
+        // Without a recorded change, the best known time is the birth.
+        if sb.version < 3 && inode.mode != 0 {
+            inode.atime = inode.crtime;
+            inode.mtime = inode.crtime;
+            inode.ctime = inode.crtime;
+        }
+        if sb.version < 6 {
+            inode.nlink = links.get(&i).copied().unwrap_or(0);
+        }
+        new.write_all(&to_bytes(&inode)).unwrap_or_else(|e| failed(&tmp_path, path, e));
+        progress.set(i + 1);
+    }
+    progress.finish();
+
+    let mut progress = Progress::new("Moving data blocks", sb.total_blocks, quiet);
+    for b in 0..sb.total_blocks {
+        if progress::interrupted() {
+            abort(&tmp_path, path);
+        }
+        let off = b * sb.block_size;
+        copy_range(&mut old, &mut new, sb.data_area_start + off, data_area_start + off, sb.block_size)
+            .unwrap_or_else(|e| failed(&tmp_path, path, e));
+        progress.set(b + 1);
+    }
+    progress.finish();
+
+    new.sync_all().unwrap_or_else(|e| failed(&tmp_path, path, e));
+    std::fs::rename(&tmp_path, path).unwrap_or_else(|e| failed(&tmp_path, path, e));
+
+    println!(
+        "Upgraded {} from version {} to {} (inode size {} -> {} bytes)",
+        path,
+        sb.version,
+        BWFS_VERSION,
+        inode_size(&sb),
+        new_inode_size
+    );
+}
+
+/// Drop the partial upgrade after an IO error and exit.
+fn failed(tmp_path: &str, path: &str, e: io::Error) -> ! {
+    let _ = std::fs::remove_file(tmp_path);
+    fail(Kind::of(&e), format!("{}: upgrade failed, image left unchanged: {}", path, e), &[("image", path.to_string())]);
+}
+
+/// Drop the partial upgrade after Ctrl+C and exit.
+fn abort(tmp_path: &str, path: &str) -> ! {
+    let _ = std::fs::remove_file(tmp_path);
+    eprintln!("\nInterrupted; {} left unchanged", path);
+    std::process::exit(EXIT_INTERRUPTED);
+}
+
+/// Copy `len` bytes from `src` at `from` to `dst` at `to`.
+fn copy_range(src: &mut File, dst: &mut File, from: u64, to: u64, len: u64) -> io::Result<()> {
+    src.seek(SeekFrom::Start(from))?;
+    dst.seek(SeekFrom::Start(to))?;
+    let copied = io::copy(&mut io::Read::take(&mut *src, len), dst)?;
+    if copied != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "image is truncated"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs_layout::testing::{dir_block, superblock, write_image, TempPath};
+    use crate::fs_layout::{read_block_map, read_dir_block, read_superblock, DirEntry};
+    use std::io::Read;
+
+    #[test]
+    fn old_images_are_upgraded_without_loss() {
+        upgrade_and_check(1);
+        upgrade_and_check(2);
+    }
+
+    /// Upgrade a small layout `version` image holding a root directory
+    /// and a file with two names, and check what it reads back as.
+    fn upgrade_and_check(version: u32) {
+        let old_sb = superblock(version);
+        let mut root = Inode::empty();
+        (root.mode, root.size, root.crtime) = (0o40755, old_sb.block_size, 100);
+        let mut file = Inode::empty();
+        (file.mode, file.size, file.direct[0], file.crtime) = (0o100644, 11, 1, 200);
+        let entries = [
+            DirEntry::new(0, ".", true),
+            DirEntry::new(0, "..", true),
+            DirEntry::new(1, "notes", false),
+            DirEntry::new(1, "again", false),
+        ];
+        let path = TempPath::new("upgrade");
+        write_image(&path, &old_sb, &[root, file], &[(0, &dir_block(&old_sb, &entries)), (1, b"hello world")]);
+
+        upgrade_image(path.as_str(), true);
+
+        let mut image = File::open(&path.0).unwrap();
+        let sb = read_superblock(&mut image).unwrap();
+        assert_eq!((sb.version, sb.inode_size, sb.epoch), (BWFS_VERSION, Inode::SIZE as u32, 1));
+        assert_eq!(sb.data_area_start, sb.inode_table_start + sb.inode_count * Inode::SIZE as u64);
+        assert_ne!(sb.feature_incompat & INCOMPAT_INDIRECT, 0);
+        assert!(!std::path::Path::new(&format!("{}.upgrading", path.as_str())).exists());
+
+        // Version 1 has no crtime to take the other times from.
+        let birth = |t| if version >= 2 { t } else { 0 };
+        let root = read_inode(&mut image, &sb, 0);
+        assert_eq!((root.mode, root.nlink, root.mtime, root.uid), (0o40755, 2, birth(100), 0));
+        let names: Vec<_> = read_dir_block(&mut image, &sb, 0).unwrap().into_iter().filter(|e| e.name_len != 0).collect();
+        assert_eq!(names, entries);
+
+        let file = read_inode(&mut image, &sb, 1);
+        assert_eq!((file.size, file.nlink), (11, 2));
+        assert_eq!([file.crtime, file.atime, file.mtime, file.ctime], [birth(200); 4]);
+        assert_eq!(read_block_map(&mut image, &sb, &file).unwrap().data, [1]);
+        let mut data = [0; 11];
+        image.seek(SeekFrom::Start(sb.data_area_start + sb.block_size)).unwrap();
+        image.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"hello world");
+        assert_eq!(read_inode(&mut image, &sb, 2), Inode::empty());
+    }
+}
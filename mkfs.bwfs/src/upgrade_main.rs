@@ -0,0 +1,39 @@
+//! CLI entry point for `bwfs-upgrade`
+//!
+//! Usage:
+//!     bwfs_upgrade --image <image_file> [--quiet]
+
+// Each tool uses its own subset of the failure kinds.
+#[allow(dead_code)]
+mod exit;
+// Shared with mkfs; only the layout readers and writers are used here.
+#[allow(dead_code)]
+mod fs_layout;
+mod progress;
+mod upgrade;
+
+use clap::Parser;
+use exit::ErrorFormat;
+
+/// Rewrite a BWFS image to the newest on-disk layout
+#[derive(Parser)]
+struct Cli {
+    /// Path to the .img file
+    #[arg(long)]
+    image: String,
+
+    /// Do not print progress.
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print the final error as text or as a JSON object
+    #[arg(long, value_enum, default_value_t = ErrorFormat::Text)]
+    error_format: ErrorFormat,
+}
+
+fn main() {
+    let args = Cli::parse();
+    exit::set_format(args.error_format);
+    progress::install_interrupt_handler();
+    upgrade::upgrade_image(&args.image, args.quiet);
+}